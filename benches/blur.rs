@@ -0,0 +1,49 @@
+use physarum::{
+    blur::{Blur, BlurBoundary},
+    grid::Grid,
+};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{distributions::Uniform, Rng};
+
+fn bench_blur(c: &mut Criterion) {
+    let (width, height) = (1024, 1024);
+    let range = Uniform::from(0.0..1.0);
+    let mut src: Vec<f32> = rand::thread_rng()
+        .sample_iter(range)
+        .take(width * height)
+        .collect();
+    let mut buf = vec![0.0; width * height];
+    let mut blur = Blur::new(width);
+
+    c.bench_function("Blur 1024x1024", |b| {
+        b.iter(|| {
+            blur.run(
+                &mut src,
+                &mut buf,
+                width,
+                height,
+                black_box(1.0),
+                1.0,
+                BlurBoundary::Periodic,
+            )
+        })
+    });
+}
+
+fn bench_diffuse_pyramid(c: &mut Criterion) {
+    let mut grid = Grid::new(1024, 1024, &mut rand::thread_rng());
+    grid.config.decay_factor = 1.0;
+
+    let mut group = c.benchmark_group("Diffuse 1024x1024, sigma 16");
+    group.bench_function("full resolution", |b| {
+        b.iter(|| grid.diffuse(black_box(16.0), 1))
+    });
+    group.bench_function("pyramid, 2 levels", |b| {
+        b.iter(|| grid.diffuse_pyramid(2, black_box(16.0)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_blur, bench_diffuse_pyramid);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use physarum::grid::Grid;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn bench_quantile(c: &mut Criterion) {
+    let grid = Grid::new(1024, 1024, &mut rand::thread_rng());
+    let mut scratch = Vec::new();
+
+    c.bench_function("Quantile 1024x1024", |b| {
+        b.iter(|| grid.quantile_with_scratch(black_box(0.999), &mut scratch))
+    });
+    c.bench_function("Approximate quantile 1024x1024", |b| {
+        b.iter(|| grid.quantile_approx(black_box(0.999), 4096))
+    });
+}
+
+criterion_group!(benches, bench_quantile);
+criterion_main!(benches);
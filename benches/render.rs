@@ -0,0 +1,25 @@
+use physarum::prelude::*;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn bench_render(c: &mut Criterion) {
+    let mut model = ModelBuilder::new()
+        .size(1024, 1024)
+        .particles(1 << 16)
+        .populations(2)
+        .seed(0)
+        .build()
+        .unwrap();
+    model.run_headless(10);
+
+    c.bench_function("Render 1024x1024", |b| b.iter(|| model.render()));
+    c.bench_function("Capture 1024x1024", |b| {
+        b.iter(|| {
+            model.save_image_data();
+            model.take_image_data()
+        })
+    });
+}
+
+criterion_group!(benches, bench_render);
+criterion_main!(benches);
@@ -0,0 +1,116 @@
+use physarum::{
+    blur::{Blur, BlurBoundary},
+    grid::{combine, AttractionMatrix, Grid},
+    prelude::*,
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{distributions::Uniform, rngs::StdRng, Rng, SeedableRng};
+
+/// Grid sizes and agent counts, keeping the density of agents per cell fixed.
+const SIZES: [(usize, usize); 3] = [(256, 1 << 14), (512, 1 << 16), (1024, 1 << 18)];
+
+fn bench_step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Step");
+    group.sample_size(20);
+    for &(size, n_particles) in &SIZES {
+        let mut model = ModelBuilder::new()
+            .size(size, size)
+            .particles(n_particles)
+            .populations(2)
+            .seed(0)
+            .build()
+            .unwrap();
+        // Let trails form first, so that the agents sense realistic fields.
+        model.run_headless(10);
+        let id = BenchmarkId::from_parameter(format!("{}x{}, {} agents", size, size, n_particles));
+        group.bench_function(id, |b| b.iter(|| model.step()));
+    }
+    group.finish();
+}
+
+fn bench_combine(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut group = c.benchmark_group("Combine");
+    for &n_populations in &[2, 4] {
+        let mut grids: Vec<_> = (0..n_populations)
+            .map(|_| Grid::new(1024, 1024, &mut rng))
+            .collect();
+        for grid in &mut grids {
+            for _ in 0..1 << 16 {
+                grid.deposit(rng.gen_range(0.0..1024.0), rng.gen_range(0.0..1024.0));
+            }
+        }
+        let mut table = AttractionMatrix::new(n_populations);
+        for i in 0..n_populations {
+            for j in 0..n_populations {
+                table.set(i, j, if i == j { 1.0 } else { -0.5 });
+            }
+        }
+        let id = BenchmarkId::from_parameter(format!("1024x1024, {} populations", n_populations));
+        group.bench_function(id, |b| b.iter(|| combine(&mut grids, table.rows())));
+    }
+    group.finish();
+}
+
+fn bench_blur_run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Blur::run");
+    for &(size, _) in &SIZES {
+        let range = Uniform::from(0.0..1.0);
+        let mut src: Vec<f32> = StdRng::seed_from_u64(0)
+            .sample_iter(range)
+            .take(size * size)
+            .collect();
+        let mut buf = vec![0.0; size * size];
+        let mut blur = Blur::new(size);
+        let id = BenchmarkId::from_parameter(format!("{}x{}", size, size));
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                blur.run(
+                    &mut src,
+                    &mut buf,
+                    size,
+                    size,
+                    black_box(1.0),
+                    0.9,
+                    BlurBoundary::Periodic,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_deposit(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut group = c.benchmark_group("Deposit");
+    for &(size, n_particles) in &SIZES {
+        let mut grid = Grid::new(size, size, &mut rng);
+        let range = Uniform::from(0.0..size as f32);
+        let deposits: Vec<_> = (0..n_particles)
+            .map(|k| (rng.sample(range), rng.sample(range), k))
+            .collect();
+        let parameter = format!("{}x{}, {} agents", size, size, n_particles);
+        group.bench_function(BenchmarkId::new("sequential", &parameter), |b| {
+            b.iter(|| {
+                for &(x, y, _) in &deposits {
+                    grid.deposit(x, y);
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("batch", &parameter), |b| {
+            let taps = grid.config.deposit_kernel.taps();
+            b.iter(|| grid.deposit_batch(&deposits, &taps, false))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_step,
+    bench_combine,
+    bench_blur_run,
+    bench_deposit
+);
+criterion_main!(benches);
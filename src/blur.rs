@@ -1,20 +1,70 @@
+use crate::parallel::prelude::*;
+
 use itertools::multizip;
-use rayon::prelude::*;
 
-#[derive(Debug)]
+/// How a blur treats the neighbors of the cells at the edges of the image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlurBoundary {
+    /// Neighbors wrap around to the opposite edge, so the total mass is preserved. Both image
+    /// dimensions must be powers of two.
+    #[default]
+    Periodic,
+    /// Neighbors beyond an edge repeat the edge value.
+    Clamp,
+    /// Neighbors beyond an edge are zero, so mass leaks out of the image.
+    Zero,
+}
+
+impl BlurBoundary {
+    /// Index of the k-th cell of a line of n cells, or None if the cell lies beyond the edges and
+    /// counts as zero.
+    fn index(self, k: isize, n: usize) -> Option<usize> {
+        match self {
+            BlurBoundary::Periodic => Some(k as usize & (n - 1)),
+            BlurBoundary::Clamp => Some(k.clamp(0, n as isize - 1) as usize),
+            BlurBoundary::Zero => (0..n as isize).contains(&k).then_some(k as usize),
+        }
+    }
+}
+
+/// Separable approximation of a Gaussian blur with successive box filters, for any row-major
+/// image of f32 values, not just trail maps. The edges are handled according to a BlurBoundary.
+/// The box filters keep running sums, which are accumulated in f64 so that rounding errors don't
+/// build up along long rows and columns.
+#[derive(Debug, Clone)]
 pub struct Blur {
-    row_buffer: Vec<f32>,
+    // Running sums of the vertical pass, one per column.
+    row_buffer: Vec<f64>,
+
+    // Box filter radii computed for the last sigma.
+    cached_boxes: Option<(f32, [usize; 2])>,
 }
 
 impl Blur {
     pub fn new(width: usize) -> Self {
         Blur {
             row_buffer: vec![0.0; width],
+            cached_boxes: None,
         }
     }
 
-    /// Blur an image with 2 box filter passes. The result will be written to the src slice, while
-    /// the buf slice is used as a scratch space.
+    /// Blur an image with a Gaussian of standard deviation sigma. The result will be written to
+    /// the src slice, while the buf slice is used as a scratch space.
+    pub fn gaussian(
+        &mut self,
+        src: &mut [f32],
+        buf: &mut [f32],
+        width: usize,
+        height: usize,
+        sigma: f32,
+        boundary: BlurBoundary,
+    ) {
+        self.run(src, buf, width, height, sigma, 1.0, boundary);
+    }
+
+    /// Blur an image with 2 box filter passes and multiply it by the decay factor. The result will
+    /// be written to the src slice, while the buf slice is used as a scratch space.
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         &mut self,
         src: &mut [f32],
@@ -23,16 +73,36 @@ impl Blur {
         height: usize,
         sigma: f32,
         decay: f32,
+        boundary: BlurBoundary,
     ) {
-        let boxes = Blur::boxes_for_gaussian::<2>(sigma);
-        self.box_blur(src, buf, width, height, boxes[0], 1.0);
-        self.box_blur(src, buf, width, height, boxes[1], decay);
+        let boxes = self.boxes(sigma);
+        self.box_blur(src, buf, width, height, boxes[0], 1.0, boundary);
+        self.box_blur(src, buf, width, height, boxes[1], decay, boundary);
+    }
+
+    /// Distance in cells over which a blur of the given sigma spreads a value, i.e. the sum of the
+    /// radii of the box filter passes. Sigmas small enough to give zero, below about 0.58, leave
+    /// the image unchanged.
+    pub fn effective_radius(sigma: f32) -> usize {
+        Blur::boxes_for_gaussian::<2>(sigma).iter().sum()
+    }
+
+    /// Box filter radii for the given sigma, recomputed only when sigma changes between calls.
+    fn boxes(&mut self, sigma: f32) -> [usize; 2] {
+        match self.cached_boxes {
+            Some((cached_sigma, boxes)) if cached_sigma == sigma => boxes,
+            _ => {
+                let boxes = Blur::boxes_for_gaussian::<2>(sigma);
+                self.cached_boxes = Some((sigma, boxes));
+                boxes
+            }
+        }
     }
 
     /// Approximate 1D Gaussian filter of standard deviation sigma with N box filter passes. Each
     /// element in the output array contains the radius of the box filter for the corresponding
     /// pass.
-    fn boxes_for_gaussian<const N: usize>(sigma: f32) -> ([usize; N]) {
+    fn boxes_for_gaussian<const N: usize>(sigma: f32) -> [usize; N] {
         let w_ideal = (12.0 * sigma * sigma / N as f32 + 1.0).sqrt();
         let mut w = w_ideal as usize;
         w -= 1 - (w & 1);
@@ -49,6 +119,7 @@ impl Blur {
 
     /// Perform one pass of the 2D box filter of the given radius. The result will be written to the
     /// src slice, while the buf slice is used as a scratch space.
+    #[allow(clippy::too_many_arguments)]
     fn box_blur(
         &mut self,
         src: &mut [f32],
@@ -57,36 +128,56 @@ impl Blur {
         height: usize,
         radius: usize,
         decay: f32,
+        boundary: BlurBoundary,
     ) {
-        self.box_blur_h(src, buf, width, radius);
-        self.box_blur_v(buf, src, width, height, radius, decay);
+        self.box_blur_h(src, buf, width, radius, boundary);
+        self.box_blur_v(buf, src, width, height, radius, decay, boundary);
     }
 
     /// Perform one pass of the 1D box filter of the given radius along x axis.
-    fn box_blur_h(&mut self, src: &[f32], dst: &mut [f32], width: usize, radius: usize) {
-        let weight = 1.0 / (2 * radius + 1) as f32;
+    fn box_blur_h(
+        &mut self,
+        src: &[f32],
+        dst: &mut [f32],
+        width: usize,
+        radius: usize,
+        boundary: BlurBoundary,
+    ) {
+        let weight = 1.0 / (2 * radius + 1) as f64;
+        let radius = radius as isize;
 
         src.par_chunks_exact(width)
             .zip(dst.par_chunks_exact_mut(width))
             .for_each(|(src_row, dst_row)| {
-                // First we build a value for the beginning of each row. We assume periodic boundary
-                // conditions, so we need to push the left index to the opposite side of the row.
-                let mut value = src_row[width - radius - 1];
+                let at = |k| boundary.index(k, width).map_or(0.0, |k| src_row[k] as f64);
+
+                // First we build a value for the beginning of each row, from the cells left of the
+                // row start as given by the boundary.
+                let mut value = at(-radius - 1);
                 for j in 0..radius {
-                    value += src_row[width - radius + j] + src_row[j];
+                    value += at(j - radius) + at(j);
                 }
 
+                // Away from the edges the neighbors are plain row elements, so only the first and
+                // last radius + 1 cells need to go through the boundary.
+                let edge = (radius as usize + 1).min(width);
+                let interior = edge..width.saturating_sub(radius as usize).max(edge);
                 for (i, dst_elem) in dst_row.iter_mut().enumerate() {
-                    let left = (i + width - radius - 1) & (width - 1);
-                    let right = (i + radius) & (width - 1);
-                    value += src_row[right] - src_row[left];
-                    *dst_elem = value * weight;
+                    if interior.contains(&i) {
+                        let (right, left) = (i + radius as usize, i - radius as usize - 1);
+                        value += src_row[right] as f64 - src_row[left] as f64;
+                    } else {
+                        let i = i as isize;
+                        value += at(i + radius) - at(i - radius - 1);
+                    }
+                    *dst_elem = (value * weight) as f32;
                 }
             })
     }
 
     /// Perform one pass of the 1D box filter of the given radius along y axis. Applies the decay
     /// factor to the destination buffer.
+    #[allow(clippy::too_many_arguments)]
     fn box_blur_v(
         &mut self,
         src: &[f32],
@@ -95,49 +186,123 @@ impl Blur {
         height: usize,
         radius: usize,
         decay: f32,
+        boundary: BlurBoundary,
     ) {
-        let weight = decay / (2 * radius + 1) as f32;
+        let weight = decay as f64 / (2 * radius + 1) as f64;
+        let radius = radius as isize;
+        // The running sums would silently skip columns if the blur was created for a narrower
+        // image, so they follow the width of the image actually being blurred.
+        self.row_buffer.resize(width, 0.0);
+        let zeros = match boundary {
+            BlurBoundary::Zero => vec![0.0; width],
+            _ => Vec::new(),
+        };
+        let row = |k| match boundary.index(k, height) {
+            Some(k) => &src[k * width..(k + 1) * width],
+            None => &zeros[..],
+        };
 
         // We don't replicate the horizontal filter logic because of the cache-unfriendly memory
         // access patterns of sequential iteration over individual columns. Instead, we iterate over
         // rows via loop interchange.
-        let offset = (height - radius - 1) * width;
-        self.row_buffer
-            .copy_from_slice(&src[offset..offset + width]);
+        for (sum, &value) in self.row_buffer.iter_mut().zip(row(-radius - 1)) {
+            *sum = value as f64;
+        }
 
         for j in 0..radius {
-            let bottom_off = (height - radius + j) * width;
-            let bottom_row = &src[bottom_off..bottom_off + width];
-            let top_off = j * width;
-            let top_row = &src[top_off..top_off + width];
-
-            for (buf, bottom, top) in multizip((&mut self.row_buffer, bottom_row, top_row)) {
-                *buf += bottom + top;
-            }
+            accumulate_rows(&mut self.row_buffer, row(j - radius), row(j));
         }
 
         // The outer loop cannot be parallelized because we need to use the buffer sequentially.
         for (i, dst_row) in dst.chunks_exact_mut(width).enumerate() {
-            let bottom_off = ((i + height - radius - 1) & (height - 1)) * width;
-            let bottom_row = &src[bottom_off..bottom_off + width];
-            let top_off = ((i + radius) & (height - 1)) * width;
-            let top_row = &src[top_off..top_off + width];
-
-            for (dst, buf, bottom, top) in
-                multizip((dst_row, &mut self.row_buffer, bottom_row, top_row))
-            {
-                *buf += top - bottom;
-                *dst = *buf * weight;
-            }
+            let i = i as isize;
+            let (bottom_row, top_row) = (row(i - radius - 1), row(i + radius));
+            slide_rows(dst_row, &mut self.row_buffer, bottom_row, top_row, weight);
         }
     }
 }
 
+/// Add the bottom and top rows to the running sum of the vertical pass.
+#[cfg(not(feature = "simd"))]
+fn accumulate_rows(buf: &mut [f64], bottom: &[f32], top: &[f32]) {
+    for (buf, &bottom, &top) in multizip((buf, bottom, top)) {
+        *buf += bottom as f64 + top as f64;
+    }
+}
+
+/// Slide the running sum of the vertical pass by one row and write the weighted result to dst.
+#[cfg(not(feature = "simd"))]
+fn slide_rows(dst: &mut [f32], buf: &mut [f64], bottom: &[f32], top: &[f32], weight: f64) {
+    for (dst, buf, &bottom, &top) in multizip((dst, buf, bottom, top)) {
+        *buf += top as f64 - bottom as f64;
+        *dst = (*buf * weight) as f32;
+    }
+}
+
+#[cfg(feature = "simd")]
+const LANES: usize = 8;
+
+#[cfg(feature = "simd")]
+type Lanes = std::simd::Simd<f32, LANES>;
+
+#[cfg(feature = "simd")]
+type WideLanes = std::simd::Simd<f64, LANES>;
+
+/// Load several columns of a row, widened to f64.
+#[cfg(feature = "simd")]
+fn load_wide(values: &[f32]) -> WideLanes {
+    use std::simd::num::SimdFloat;
+    Lanes::from_slice(values).cast()
+}
+
+/// Add the bottom and top rows to the running sum of the vertical pass, several columns at a time.
+#[cfg(feature = "simd")]
+fn accumulate_rows(buf: &mut [f64], bottom: &[f32], top: &[f32]) {
+    let n = buf.len() - buf.len() % LANES;
+    for ((buf, bottom), top) in buf[..n]
+        .chunks_exact_mut(LANES)
+        .zip(bottom.chunks_exact(LANES))
+        .zip(top.chunks_exact(LANES))
+    {
+        let sum = WideLanes::from_slice(buf) + (load_wide(bottom) + load_wide(top));
+        sum.copy_to_slice(buf);
+    }
+    for (buf, &bottom, &top) in multizip((&mut buf[n..], &bottom[n..], &top[n..])) {
+        *buf += bottom as f64 + top as f64;
+    }
+}
+
+/// Slide the running sum of the vertical pass by one row and write the weighted result to dst,
+/// several columns at a time.
+#[cfg(feature = "simd")]
+fn slide_rows(dst: &mut [f32], buf: &mut [f64], bottom: &[f32], top: &[f32], weight: f64) {
+    use std::simd::num::SimdFloat;
+    let n = buf.len() - buf.len() % LANES;
+    let weights = WideLanes::splat(weight);
+    for (((dst, buf), bottom), top) in dst[..n]
+        .chunks_exact_mut(LANES)
+        .zip(buf[..n].chunks_exact_mut(LANES))
+        .zip(bottom.chunks_exact(LANES))
+        .zip(top.chunks_exact(LANES))
+    {
+        let sum = WideLanes::from_slice(buf) + (load_wide(top) - load_wide(bottom));
+        sum.copy_to_slice(buf);
+        (sum * weights).cast::<f32>().copy_to_slice(dst);
+    }
+    for (dst, buf, &bottom, &top) in
+        multizip((&mut dst[n..], &mut buf[n..], &bottom[n..], &top[n..]))
+    {
+        *buf += top as f64 - bottom as f64;
+        *dst = (*buf * weight) as f32;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn test_blur() {
         // The values from this test were obtained using Python's code:
         // a = np.random.rand(8, 8)
@@ -161,7 +326,7 @@ mod tests {
         let mut dst = vec![0.0; width * height];
         let mut blur = Blur::new(width);
 
-        blur.box_blur_h(&src, &mut dst, width, 1);
+        blur.box_blur_h(&src, &mut dst, width, 1, BlurBoundary::Periodic);
         let mut sol: Vec<f32> = vec![
             0.33921536, 0.13621319, 0.04954382, 0.26381392, 0.46308973, 0.49737768, 0.47066893,
             0.37277121, 0.44850051, 0.37332688, 0.21674603, 0.36333409, 0.48751974, 0.70454735,
@@ -178,7 +343,15 @@ mod tests {
             assert!((v1 - v2).abs() < 1e-6);
         }
 
-        blur.box_blur_v(&src, &mut dst, width, height, 1, 1.0);
+        blur.box_blur_v(
+            &src,
+            &mut dst,
+            width,
+            height,
+            1,
+            1.0,
+            BlurBoundary::Periodic,
+        );
         sol = vec![
             0.50403511, 0.38229549, 0.19629186, 0.29968528, 0.51910173, 0.61901508, 0.44607546,
             0.53130095, 0.52355005, 0.177688, 0.16011561, 0.08289763, 0.51645436, 0.46399322,
@@ -195,7 +368,15 @@ mod tests {
             assert!((v1 - v2).abs() < 1e-6);
         }
 
-        blur.box_blur(&mut src, &mut dst, width, height, 1, 1.0);
+        blur.box_blur(
+            &mut src,
+            &mut dst,
+            width,
+            height,
+            1,
+            1.0,
+            BlurBoundary::Periodic,
+        );
         sol = vec![
             0.47254385, 0.36087415, 0.29275754, 0.33835963, 0.47926736, 0.52806409, 0.5321305,
             0.49380384, 0.46566129, 0.28711789, 0.14023375, 0.25315587, 0.3544484, 0.45375601,
@@ -213,6 +394,173 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_gaussian() {
+        let (width, height, sigma) = (64, 32, 3.0);
+        let mut src = vec![0.0; width * height];
+        let mut buf = vec![0.0; width * height];
+        src[16 * width + 32] = 1.0;
+        Blur::new(width).gaussian(
+            &mut src,
+            &mut buf,
+            width,
+            height,
+            sigma,
+            BlurBoundary::Periodic,
+        );
+
+        let total: f32 = src.iter().sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        let (mut var_x, mut var_y) = (0.0, 0.0);
+        for (k, value) in src.iter().enumerate() {
+            let (dx, dy) = ((k % width) as f32 - 32.0, (k / width) as f32 - 16.0);
+            var_x += value * dx * dx;
+            var_y += value * dy * dy;
+        }
+        assert!((var_x.sqrt() - sigma).abs() < 0.1 * sigma);
+        assert!((var_y - var_x).abs() < 1e-4);
+
+        // The profile decreases away from the center.
+        let row = &src[16 * width..17 * width];
+        assert!(row[32..].windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(row[..33].windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_blur_boundary() {
+        let src: Vec<f32> = (0..16).map(|k| ((k * 7) % 5) as f32).collect();
+        // 3x3 box average with the neighbors given by the boundary.
+        let reference = |boundary: BlurBoundary| {
+            let mut dst = vec![0.0; 16];
+            for (k, value) in dst.iter_mut().enumerate() {
+                let (i, j) = ((k % 4) as isize, (k / 4) as isize);
+                for dj in -1..=1 {
+                    for di in -1..=1 {
+                        if let (Some(i), Some(j)) =
+                            (boundary.index(i + di, 4), boundary.index(j + dj, 4))
+                        {
+                            *value += src[j * 4 + i] / 9.0;
+                        }
+                    }
+                }
+            }
+            dst
+        };
+
+        let mut blurred = Vec::new();
+        for &boundary in &[
+            BlurBoundary::Periodic,
+            BlurBoundary::Clamp,
+            BlurBoundary::Zero,
+        ] {
+            let mut data = src.clone();
+            let mut buf = vec![0.0; 16];
+            Blur::new(4).box_blur(&mut data, &mut buf, 4, 4, 1, 1.0, boundary);
+            for (v1, v2) in data.iter().zip(reference(boundary)) {
+                assert!((v1 - v2).abs() < 1e-5);
+            }
+            blurred.push(data);
+        }
+
+        // Only the edges differ between the boundaries.
+        let (periodic, clamp) = (&blurred[0], &blurred[1]);
+        for k in [5, 6, 9, 10] {
+            assert!((periodic[k] - clamp[k]).abs() < 1e-5);
+        }
+        assert!((0..4).any(|k| (periodic[k] - clamp[k]).abs() > 1e-3));
+        let total = |data: &[f32]| data.iter().sum::<f32>();
+        assert!((total(periodic) - total(&src)).abs() < 1e-4);
+        assert!(total(&blurred[2]) < total(&src));
+    }
+
+    #[test]
+    fn test_blur_non_square() {
+        // Box average of the given radius with the neighbors given by the boundary.
+        let reference = |src: &[f32], width: usize, height: usize, radius: isize, boundary| {
+            let mut dst = vec![0.0; width * height];
+            let weight = 1.0 / ((2 * radius + 1) * (2 * radius + 1)) as f32;
+            for (k, value) in dst.iter_mut().enumerate() {
+                let (i, j) = ((k % width) as isize, (k / width) as isize);
+                for dj in -radius..=radius {
+                    for di in -radius..=radius {
+                        if let (Some(i), Some(j)) = (
+                            BlurBoundary::index(boundary, i + di, width),
+                            BlurBoundary::index(boundary, j + dj, height),
+                        ) {
+                            *value += src[j * width + i] * weight;
+                        }
+                    }
+                }
+            }
+            dst
+        };
+
+        // The same blur is reused across shapes, which must not depend on the width it was created
+        // for.
+        let mut blur = Blur::new(4);
+        for &(width, height) in &[(16, 8), (8, 16), (32, 4)] {
+            let src: Vec<f32> = (0..width * height)
+                .map(|k| ((k * 7919) % 13) as f32)
+                .collect();
+            for &boundary in &[
+                BlurBoundary::Periodic,
+                BlurBoundary::Clamp,
+                BlurBoundary::Zero,
+            ] {
+                for radius in [1, 2, 5] {
+                    let mut data = src.clone();
+                    let mut buf = vec![0.0; width * height];
+                    blur.box_blur(&mut data, &mut buf, width, height, radius, 1.0, boundary);
+                    let expected = reference(&src, width, height, radius as isize, boundary);
+                    for (v1, v2) in data.iter().zip(&expected) {
+                        assert!(
+                            (v1 - v2).abs() < 1e-4,
+                            "{}x{} {:?} radius {}",
+                            width,
+                            height,
+                            boundary,
+                            radius
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_running_sum_accuracy() {
+        // A large offset makes every step of the running sums lose low bits in f32, which adds up
+        // along a long row or column.
+        let n = 4096;
+        let line: Vec<f32> = (0..n)
+            .map(|k| 1000.0 + ((k * 7919) % 1000) as f32 / 1000.0)
+            .collect();
+        let radius = 5;
+        let expected: Vec<f64> = (0..n)
+            .map(|i| {
+                let sum: f64 = (i as isize - radius..=i as isize + radius)
+                    .map(|k| line[k.rem_euclid(n as isize) as usize] as f64)
+                    .sum();
+                sum / (2 * radius + 1) as f64
+            })
+            .collect();
+
+        let mut blur = Blur::new(n);
+        let mut dst = vec![0.0; n];
+        blur.box_blur_h(&line, &mut dst, n, radius as usize, BlurBoundary::Periodic);
+        for (v1, v2) in dst.iter().zip(&expected) {
+            assert!((*v1 as f64 - v2).abs() < 1e-4);
+        }
+
+        let mut blur = Blur::new(1);
+        let mut dst = vec![0.0; n];
+        let (radius, decay) = (radius as usize, 1.0);
+        blur.box_blur_v(&line, &mut dst, 1, n, radius, decay, BlurBoundary::Periodic);
+        for (v1, v2) in dst.iter().zip(&expected) {
+            assert!((*v1 as f64 - v2).abs() < 1e-4);
+        }
+    }
+
     #[test]
     fn test_boxes_for_gaussian() {
         let boxes = Blur::boxes_for_gaussian::<3>(1.5);
@@ -224,4 +572,36 @@ mod tests {
         let boxes = Blur::boxes_for_gaussian::<3>(2.5);
         assert_eq!(boxes, [2, 2, 2]);
     }
+
+    #[test]
+    fn test_effective_radius() {
+        let table = [
+            (0.0, 0),
+            (0.5, 0),
+            (0.75, 1),
+            (1.0, 1),
+            (1.5, 3),
+            (2.0, 4),
+            (3.0, 6),
+            (5.0, 11),
+            (10.0, 23),
+        ];
+        for &(sigma, radius) in &table {
+            assert_eq!(Blur::effective_radius(sigma), radius, "sigma = {}", sigma);
+        }
+
+        // A zero radius blur is a no-op.
+        let src: Vec<f32> = (0..64).map(|k| (k % 7) as f32).collect();
+        let (mut data, mut buf) = (src.clone(), vec![0.0; 64]);
+        Blur::new(8).gaussian(&mut data, &mut buf, 8, 8, 0.5, BlurBoundary::Periodic);
+        assert_eq!(data, src);
+    }
+
+    #[test]
+    fn test_cached_boxes() {
+        let mut blur = Blur::new(8);
+        for &sigma in &[1.0, 1.0, 2.5, 1.8, 1.8, 1.0] {
+            assert_eq!(blur.boxes(sigma), Blur::boxes_for_gaussian::<2>(sigma));
+        }
+    }
 }
@@ -0,0 +1,166 @@
+use crate::grid::Param;
+
+use std::fmt::{Display, Formatter};
+
+/// A population parameter outside of its valid range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub param: Param,
+    pub value: f32,
+    /// Description of the valid range.
+    pub expected: &'static str,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} must be {}, got {}.",
+            self.param, self.expected, self.value
+        )
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Errors reported when configuring a model.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Grid dimensions must be powers of two.
+    InvalidGridSize { width: usize, height: usize },
+    /// A count that must be positive is zero.
+    ZeroCount(&'static str),
+    /// A thread pool could not be created.
+    ThreadPool(String),
+    /// A frame buffer has the wrong number of bytes.
+    BufferSize { expected: usize, actual: usize },
+    /// Images to be combined have different dimensions.
+    ImageSizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// Reading or writing a file failed.
+    Io(String),
+    /// A step order must contain every phase exactly once.
+    InvalidStepOrder,
+    /// An image format that cannot be written.
+    UnsupportedFormat(String),
+    /// An image quality outside of 1 to 100.
+    InvalidQuality(u8),
+    /// A population configuration has a parameter outside of its valid range.
+    InvalidConfig(ConfigError),
+    /// Grids do not match the populations or dimensions of the model.
+    GridMismatch,
+    /// An attraction matrix must have as many columns in every row as it has rows.
+    NonSquareMatrix { rows: usize, columns: usize },
+    /// The trail of a population holds NaN or infinite values, typically due to a decay factor
+    /// above one or a huge deposition amount.
+    NonFinite {
+        population_id: usize,
+        iteration: usize,
+    },
+    /// A population id beyond the populations of the model.
+    InvalidPopulation {
+        population_id: usize,
+        n_populations: usize,
+    },
+    /// A diffusivity that is negative, infinite or NaN.
+    InvalidDiffusivity(f32),
+    /// A diffusivity whose effective blur radius is zero, so the trails would never spread.
+    ZeroBlurRadius(f32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidGridSize { width, height } => write!(
+                f,
+                "Grid dimensions must be a power of two, got {}x{}.",
+                width, height
+            ),
+            Error::ZeroCount(what) => write!(f, "The number of {} must be positive.", what),
+            Error::ThreadPool(reason) => write!(f, "Failed to build a thread pool: {}", reason),
+            Error::BufferSize { expected, actual } => write!(
+                f,
+                "Frame buffer must hold {} bytes, got {}.",
+                expected, actual
+            ),
+            Error::ImageSizeMismatch { expected, actual } => write!(
+                f,
+                "Images must all be {}x{}, got {}x{}.",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            Error::Io(reason) => write!(f, "I/O error: {}", reason),
+            Error::InvalidStepOrder => write!(f, "Every step phase must appear exactly once."),
+            Error::UnsupportedFormat(format) => {
+                write!(f, "Images cannot be written in the {} format.", format)
+            }
+            Error::InvalidQuality(quality) => {
+                write!(f, "Image quality must be in 1..=100, got {}.", quality)
+            }
+            Error::InvalidConfig(err) => write!(f, "Invalid population configuration: {}", err),
+            Error::GridMismatch => write!(
+                f,
+                "Grids must match the number of populations and the dimensions of the model."
+            ),
+            Error::NonSquareMatrix { rows, columns } => write!(
+                f,
+                "Attraction matrix must be square, got a row of {} columns in {} rows.",
+                columns, rows
+            ),
+            Error::NonFinite {
+                population_id,
+                iteration,
+            } => write!(
+                f,
+                "The trail of population {} is not finite at iteration {}.",
+                population_id, iteration
+            ),
+            Error::InvalidPopulation {
+                population_id,
+                n_populations,
+            } => write!(
+                f,
+                "Population {} does not exist, the model has {} populations.",
+                population_id, n_populations
+            ),
+            Error::InvalidDiffusivity(diffusivity) => write!(
+                f,
+                "Diffusivity must be finite and non-negative, got {}.",
+                diffusivity
+            ),
+            Error::ZeroBlurRadius(diffusivity) => write!(
+                f,
+                "Diffusivity {} rounds to a zero blur radius, trails would not diffuse.",
+                diffusivity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::InvalidConfig(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err.to_string())
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<png::EncodingError> for Error {
+    fn from(err: png::EncodingError) -> Self {
+        Error::Io(err.to_string())
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Self {
+        Error::Io(err.to_string())
+    }
+}
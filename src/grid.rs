@@ -1,32 +1,224 @@
-use crate::blur::Blur;
+use crate::{
+    blur::{Blur, BlurBoundary},
+    error::{ConfigError, Error},
+    palette::Palette,
+    parallel::prelude::*,
+    render::{self, WHITE_POINT_QUANTILE, WHITE_POINT_SCALE},
+};
 
+use image::{imageops, imageops::FilterType, GrayImage, RgbImage};
 use rand::{distributions::Uniform, Rng};
+use rand_distr::StandardNormal;
 
-use std::fmt::{Display, Formatter};
+use itertools::multizip;
+
+#[cfg(feature = "native")]
+use std::path::Path;
+use std::{
+    f32::consts::PI,
+    fmt::{Display, Formatter},
+};
+
+/// A scalar parameter of a population configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Param {
+    SensorDistance,
+    StepDistance,
+    SensorAngle,
+    RotationAngle,
+    DecayFactor,
+    DepositionAmount,
+    Inertia,
+    SpeedJitter,
+    StepJitter,
+    DepositSaturation,
+    DepositOffset,
+    /// Radius of a disk deposit kernel, which only applies to populations that deposit with one.
+    DepositRadius,
+    /// Number of sensors, rounded to the nearest integer when set.
+    NSensors,
+    TurnRandomness,
+    TieBreakBias,
+    /// Parameters of the lifecycle, which only apply to populations that have one.
+    EnergyGain,
+    EnergyLoss,
+    SplitThreshold,
+    InitialEnergy,
+}
+
+/// Energy budget that lets a population grow and shrink with the trail density. Agents gain energy
+/// in proportion to the strongest trail they sense, lose a fixed amount every step, die once their
+/// energy is depleted and split in two once it exceeds a threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lifecycle {
+    pub energy_gain: f32,
+    pub energy_loss: f32,
+    pub split_threshold: f32,
+    pub initial_energy: f32,
+}
+
+/// Shape of the domain the agents live in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GridShape {
+    /// The whole grid, with periodic edges.
+    #[default]
+    Rect,
+    /// The disk inscribed in the grid. Cells outside of it hold no trail, agents bounce off its
+    /// boundary, and sensors beyond the grid edges are clamped rather than wrapped.
+    Disk,
+}
+
+impl GridShape {
+    /// Whether a position lies inside the shape on a grid of the given size.
+    pub fn contains(&self, x: f32, y: f32, width: usize, height: usize) -> bool {
+        match self {
+            GridShape::Rect => true,
+            GridShape::Disk => {
+                let (cx, cy) = (0.5 * width as f32, 0.5 * height as f32);
+                let r = cx.min(cy);
+                (x - cx).powi(2) + (y - cy).powi(2) <= r * r
+            }
+        }
+    }
+}
+
+/// How agents decide which way to turn.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Steering {
+    /// Compare the sensed field ahead of the agent and at the left and right sensors.
+    #[default]
+    Sensors,
+    /// Turn towards the gradient of the sensed field ahead of the agent, by at most the rotation
+    /// angle. This samples the field in four cells around a single sensor.
+    Gradient,
+}
+
+/// Footprint of the trail an agent deposits, centered on its deposit position. Every kernel spreads
+/// the same total amount, so only the shape of the deposit changes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DepositKernel {
+    /// The whole amount lands in a single cell.
+    #[default]
+    Point,
+    /// The 3x3 cells around the position, weighted by the binomial approximation of a Gaussian:
+    /// 1/4, 1/8 and 1/16 for the center, edge and corner cells.
+    Gaussian3x3,
+    /// The cells whose offsets lie within the given radius, weighted equally.
+    Disk(f32),
+}
+
+impl DepositKernel {
+    /// Cell offsets of the kernel with their weights, which sum to one.
+    pub fn taps(self) -> Vec<(f32, f32, f32)> {
+        match self {
+            DepositKernel::Point => vec![(0.0, 0.0, 1.0)],
+            DepositKernel::Gaussian3x3 => {
+                let weights = [0.25, 0.5, 0.25];
+                (0..9)
+                    .map(|k| {
+                        let (i, j) = (k % 3, k / 3);
+                        (i as f32 - 1.0, j as f32 - 1.0, weights[i] * weights[j])
+                    })
+                    .collect()
+            }
+            DepositKernel::Disk(radius) => {
+                let r = radius.max(0.0).floor() as i32;
+                let offsets: Vec<_> = (-r..=r)
+                    .flat_map(|j| (-r..=r).map(move |i| (i as f32, j as f32)))
+                    .filter(|(i, j)| i * i + j * j <= radius * radius)
+                    .collect();
+                if offsets.is_empty() {
+                    return vec![(0.0, 0.0, 1.0)];
+                }
+                let weight = 1.0 / offsets.len() as f32;
+                offsets.into_iter().map(|(i, j)| (i, j, weight)).collect()
+            }
+        }
+    }
+}
+
+/// Settings of an additional chemical layer of a grid, e.g. a repellent. Agents of the population
+/// deposit into every layer, and their sensed field includes each extra layer scaled by its signed
+/// sensing weight. Unlike the primary trail, extra layers are only sensed by their own population.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelConfig {
+    pub deposition_amount: f32,
+    pub decay_factor: f32,
+    /// Standard deviation of the Gaussian blur applied to the layer every step.
+    pub diffusivity: f32,
+    pub sensing_weight: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    config: ChannelConfig,
+    data: Vec<f32>,
+}
 
 /// A population configuration.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PopulationConfig {
     pub sensor_distance: f32,
     pub step_distance: f32,
     pub sensor_angle: f32,
     pub rotation_angle: f32,
-
-    decay_factor: f32,
-    deposition_amount: f32,
+    pub decay_factor: f32,
+    pub deposition_amount: f32,
+    /// Fraction of the angular velocity kept between steps. Zero makes agents turn by exactly the
+    /// rotation angle, higher values smooth their trajectories.
+    pub inertia: f32,
+    /// Standard deviation of the per-agent step distance multiplier, drawn once at construction
+    /// from a normal distribution with unit mean.
+    pub speed_jitter: f32,
+    /// Standard deviation of the step distance multiplier, drawn every step from a normal
+    /// distribution with unit mean and clamped to be non-negative.
+    pub step_jitter: f32,
+    /// Trail value at which deposits are halved. Agents deposit `deposition_amount / (1 + trail /
+    /// deposit_saturation)`, so an infinite saturation makes deposits constant.
+    pub deposit_saturation: f32,
+    /// Distance behind the agent at which it deposits.
+    pub deposit_offset: f32,
+    /// Agent energy dynamics. Without them the number of agents stays fixed.
+    pub lifecycle: Option<Lifecycle>,
+    pub steering: Steering,
+    /// Number of sensors fanned evenly across [-sensor_angle, sensor_angle] when steering with
+    /// sensors. Three sensors sit to the left, ahead and to the right of the agent.
+    pub n_sensors: usize,
+    /// Strongest sensor reading below which agents move without depositing, which keeps agents
+    /// wandering through empty space from cluttering the network. Zero or less always deposits.
+    pub deposit_threshold: f32,
+    pub deposit_kernel: DepositKernel,
+    /// Probability of turning by a uniformly random fraction of the rotation angle every step,
+    /// whatever the sensors read. Zero always follows the sensors.
+    pub turn_randomness: f32,
+    /// Probability of turning right rather than left when the sensor ahead is the weakest, one
+    /// half by default.
+    pub tie_break_bias: f32,
 }
 
 impl Display for PopulationConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{\n  Sensor Distance: {},\n  Step Distance: {},\n  Sensor Angle: {},\n  Rotation Angle: {},\n  Decay Factor: {},\n  Deposition Amount: {},\n}}",
+            "{{\n  Sensor Distance: {},\n  Step Distance: {},\n  Sensor Angle: {},\n  Rotation Angle: {},\n  Decay Factor: {},\n  Deposition Amount: {},\n  Inertia: {},\n  Speed Jitter: {},\n  Step Jitter: {},\n  Deposit Saturation: {},\n  Deposit Offset: {},\n  Lifecycle: {:?},\n  Steering: {:?},\n  Sensors: {},\n  Deposit Threshold: {},\n  Deposit Kernel: {:?},\n  Turn Randomness: {},\n  Tie-Break Bias: {},\n}}",
             self.sensor_distance,
             self.step_distance,
             self.sensor_angle,
             self.rotation_angle,
             self.decay_factor,
-            self.deposition_amount
+            self.deposition_amount,
+            self.inertia,
+            self.speed_jitter,
+            self.step_jitter,
+            self.deposit_saturation,
+            self.deposit_offset,
+            self.lifecycle,
+            self.steering,
+            self.n_sensors,
+            self.deposit_threshold,
+            self.deposit_kernel,
+            self.turn_randomness,
+            self.tie_break_bias
         )
     }
 }
@@ -44,43 +236,433 @@ impl PopulationConfig {
     const DEPOSITION_AMOUNT_MAX: f32 = 5.0;
     const DECAY_FACTOR_MIN: f32 = 0.1;
     const DECAY_FACTOR_MAX: f32 = 0.1;
+    const INERTIA_MIN: f32 = 0.0;
+    const INERTIA_MAX: f32 = 0.0;
+    const SPEED_JITTER_MIN: f32 = 0.0;
+    const SPEED_JITTER_MAX: f32 = 0.0;
+    const STEP_JITTER_MIN: f32 = 0.0;
+    const STEP_JITTER_MAX: f32 = 0.0;
+    const DEPOSIT_OFFSET_MIN: f32 = 0.0;
+    const DEPOSIT_OFFSET_MAX: f32 = 0.0;
+    /// Largest disk deposit kernel, which already spreads every deposit over about 800 cells.
+    const DEPOSIT_RADIUS_MAX: f32 = 16.0;
 
     /// Construct a random configuration.
     pub fn new<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        Self::sample(&ConfigSampler::default(), rng)
+    }
+
+    /// Construct a random configuration with parameters drawn from the distributions of a
+    /// sampler.
+    pub fn sample<R: Rng + ?Sized>(sampler: &ConfigSampler, rng: &mut R) -> Self {
         PopulationConfig {
-            sensor_distance: rng.gen_range(Self::SENSOR_DISTANCE_MIN..=Self::SENSOR_DISTANCE_MAX),
-            step_distance: rng.gen_range(Self::STEP_DISTANCE_MIN..=Self::STEP_DISTANCE_MAX),
-            decay_factor: rng.gen_range(Self::DECAY_FACTOR_MIN..=Self::DECAY_FACTOR_MAX),
-            sensor_angle: rng
-                .gen_range(Self::SENSOR_ANGLE_MIN..=Self::SENSOR_ANGLE_MAX)
-                .to_radians(),
-            rotation_angle: rng
-                .gen_range(Self::ROTATION_ANGLE_MIN..=Self::ROTATION_ANGLE_MAX)
-                .to_radians(),
-            deposition_amount: rng
-                .gen_range(Self::DEPOSITION_AMOUNT_MIN..=Self::DEPOSITION_AMOUNT_MAX),
+            sensor_distance: sampler.sensor_distance.sample(rng),
+            step_distance: sampler.step_distance.sample(rng),
+            decay_factor: sampler.decay_factor.sample(rng),
+            sensor_angle: sampler.sensor_angle.sample(rng),
+            rotation_angle: sampler.rotation_angle.sample(rng),
+            deposition_amount: sampler.deposition_amount.sample(rng),
+            inertia: sampler.inertia.sample(rng),
+            speed_jitter: sampler.speed_jitter.sample(rng),
+            step_jitter: sampler.step_jitter.sample(rng),
+            deposit_saturation: f32::INFINITY,
+            deposit_offset: sampler.deposit_offset.sample(rng),
+            lifecycle: None,
+            steering: Steering::default(),
+            n_sensors: 3,
+            deposit_threshold: 0.0,
+            deposit_kernel: DepositKernel::Point,
+            turn_randomness: 0.0,
+            tie_break_bias: 0.5,
+        }
+    }
+
+    /// Construct a configuration from the given parameters, with angles in radians. The remaining
+    /// parameters keep their neutral defaults.
+    pub fn from_params(
+        sensor_distance: f32,
+        step_distance: f32,
+        sensor_angle: f32,
+        rotation_angle: f32,
+        decay_factor: f32,
+        deposition_amount: f32,
+    ) -> Result<Self, ConfigError> {
+        let config = PopulationConfig {
+            sensor_distance,
+            step_distance,
+            sensor_angle,
+            rotation_angle,
+            decay_factor,
+            deposition_amount,
+            inertia: 0.0,
+            speed_jitter: 0.0,
+            step_jitter: 0.0,
+            deposit_saturation: f32::INFINITY,
+            deposit_offset: 0.0,
+            lifecycle: None,
+            steering: Steering::default(),
+            n_sensors: 3,
+            deposit_threshold: 0.0,
+            deposit_kernel: DepositKernel::Point,
+            turn_randomness: 0.0,
+            tie_break_bias: 0.5,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the parameters are in the ranges the simulation can handle: angles within
+    /// [0, π] radians, non-negative distances, a decay factor within (0, 1], a positive
+    /// deposition amount, an inertia within [0, 1), non-negative jitters, a positive deposit
+    /// saturation, which may be infinite, and non-negative lifecycle rates and energies with a
+    /// positive split threshold. All other values must be finite.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let check = |param, value: f32, valid: bool, expected| {
+            if valid && value.is_finite() {
+                Ok(())
+            } else {
+                Err(ConfigError {
+                    param,
+                    value,
+                    expected,
+                })
+            }
+        };
+        let angle = |value: f32| (0.0..=PI).contains(&value);
+        check(
+            Param::SensorDistance,
+            self.sensor_distance,
+            self.sensor_distance >= 0.0,
+            "non-negative",
+        )?;
+        check(
+            Param::StepDistance,
+            self.step_distance,
+            self.step_distance >= 0.0,
+            "non-negative",
+        )?;
+        check(
+            Param::SensorAngle,
+            self.sensor_angle,
+            angle(self.sensor_angle),
+            "within [0, π] radians",
+        )?;
+        check(
+            Param::RotationAngle,
+            self.rotation_angle,
+            angle(self.rotation_angle),
+            "within [0, π] radians",
+        )?;
+        check(
+            Param::DecayFactor,
+            self.decay_factor,
+            self.decay_factor > 0.0 && self.decay_factor <= 1.0,
+            "within (0, 1]",
+        )?;
+        check(
+            Param::DepositionAmount,
+            self.deposition_amount,
+            self.deposition_amount > 0.0,
+            "positive",
+        )?;
+        check(
+            Param::Inertia,
+            self.inertia,
+            (0.0..1.0).contains(&self.inertia),
+            "within [0, 1)",
+        )?;
+        check(
+            Param::SpeedJitter,
+            self.speed_jitter,
+            self.speed_jitter >= 0.0,
+            "non-negative",
+        )?;
+        check(
+            Param::StepJitter,
+            self.step_jitter,
+            self.step_jitter >= 0.0,
+            "non-negative",
+        )?;
+        // An infinite saturation disables it, so only this value may be infinite.
+        if self.deposit_saturation.is_nan() || self.deposit_saturation <= 0.0 {
+            return Err(ConfigError {
+                param: Param::DepositSaturation,
+                value: self.deposit_saturation,
+                expected: "positive",
+            });
+        }
+        check(Param::DepositOffset, self.deposit_offset, true, "finite")?;
+        if let DepositKernel::Disk(radius) = self.deposit_kernel {
+            check(
+                Param::DepositRadius,
+                radius,
+                (0.0..=Self::DEPOSIT_RADIUS_MAX).contains(&radius),
+                "within [0, 16]",
+            )?;
+        }
+        check(
+            Param::NSensors,
+            self.n_sensors as f32,
+            self.n_sensors > 0,
+            "positive",
+        )?;
+        check(
+            Param::TurnRandomness,
+            self.turn_randomness,
+            (0.0..=1.0).contains(&self.turn_randomness),
+            "within [0, 1]",
+        )?;
+        check(
+            Param::TieBreakBias,
+            self.tie_break_bias,
+            (0.0..=1.0).contains(&self.tie_break_bias),
+            "within [0, 1]",
+        )?;
+        if let Some(lifecycle) = &self.lifecycle {
+            check(
+                Param::EnergyGain,
+                lifecycle.energy_gain,
+                lifecycle.energy_gain >= 0.0,
+                "non-negative",
+            )?;
+            check(
+                Param::EnergyLoss,
+                lifecycle.energy_loss,
+                lifecycle.energy_loss >= 0.0,
+                "non-negative",
+            )?;
+            check(
+                Param::SplitThreshold,
+                lifecycle.split_threshold,
+                lifecycle.split_threshold > 0.0,
+                "positive",
+            )?;
+            check(
+                Param::InitialEnergy,
+                lifecycle.initial_energy,
+                lifecycle.initial_energy >= 0.0,
+                "non-negative",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The same configuration for a grid whose resolution is scaled by the given ratio. The
+    /// lengths measured in grid cells, i.e. the sensor distance, the step distance and the deposit
+    /// offset, are multiplied by the ratio. Angles, the decay factor, the deposition amount and
+    /// the remaining dimensionless parameters stay the same.
+    pub fn scaled(&self, ratio: f32) -> Self {
+        PopulationConfig {
+            sensor_distance: self.sensor_distance * ratio,
+            step_distance: self.step_distance * ratio,
+            deposit_offset: self.deposit_offset * ratio,
+            deposit_kernel: match self.deposit_kernel {
+                DepositKernel::Disk(radius) => DepositKernel::Disk(radius * ratio),
+                kernel => kernel,
+            },
+            ..self.clone()
+        }
+    }
+
+    /// Set a parameter value. Angles are in radians. Lifecycle parameters are ignored by
+    /// populations without a lifecycle, and the deposit radius by those without a disk kernel.
+    pub fn set(&mut self, param: Param, value: f32) {
+        let field = match (param, &mut self.lifecycle) {
+            (Param::SensorDistance, _) => &mut self.sensor_distance,
+            (Param::StepDistance, _) => &mut self.step_distance,
+            (Param::SensorAngle, _) => &mut self.sensor_angle,
+            (Param::RotationAngle, _) => &mut self.rotation_angle,
+            (Param::DecayFactor, _) => &mut self.decay_factor,
+            (Param::DepositionAmount, _) => &mut self.deposition_amount,
+            (Param::Inertia, _) => &mut self.inertia,
+            (Param::SpeedJitter, _) => &mut self.speed_jitter,
+            (Param::StepJitter, _) => &mut self.step_jitter,
+            (Param::DepositSaturation, _) => &mut self.deposit_saturation,
+            (Param::DepositOffset, _) => &mut self.deposit_offset,
+            (Param::TurnRandomness, _) => &mut self.turn_randomness,
+            (Param::TieBreakBias, _) => &mut self.tie_break_bias,
+            (Param::NSensors, _) => {
+                self.n_sensors = value.round() as usize;
+                return;
+            }
+            (Param::DepositRadius, _) => match &mut self.deposit_kernel {
+                DepositKernel::Disk(radius) => radius,
+                _ => return,
+            },
+            (Param::EnergyGain, Some(lifecycle)) => &mut lifecycle.energy_gain,
+            (Param::EnergyLoss, Some(lifecycle)) => &mut lifecycle.energy_loss,
+            (Param::SplitThreshold, Some(lifecycle)) => &mut lifecycle.split_threshold,
+            (Param::InitialEnergy, Some(lifecycle)) => &mut lifecycle.initial_energy,
+            (_, None) => return,
+        };
+        *field = value;
+    }
+}
+
+/// Distribution that a configuration parameter is drawn from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamDistribution {
+    /// Uniform over [min, max].
+    Uniform { min: f32, max: f32 },
+    /// Normal with the given mean and standard deviation, truncated to [min, max].
+    TruncatedNormal {
+        mean: f32,
+        std: f32,
+        min: f32,
+        max: f32,
+    },
+}
+
+impl ParamDistribution {
+    /// Number of draws after which a truncated normal gives up and clamps its last draw.
+    const MAX_REJECTIONS: usize = 64;
+
+    /// Check that the bounds are finite and ordered, since sampling panics otherwise. The error
+    /// reports the lower bound.
+    pub fn validate(&self, param: Param) -> Result<(), Error> {
+        let (min, max) = match *self {
+            ParamDistribution::Uniform { min, max } => (min, max),
+            ParamDistribution::TruncatedNormal { min, max, .. } => (min, max),
+        };
+        if min.is_finite() && max.is_finite() && min <= max {
+            Ok(())
+        } else {
+            Err(ConfigError {
+                param,
+                value: min,
+                expected: "a finite lower bound no greater than the upper bound",
+            }
+            .into())
+        }
+    }
+
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f32 {
+        match *self {
+            ParamDistribution::Uniform { min, max } => rng.gen_range(min..=max),
+            ParamDistribution::TruncatedNormal {
+                mean,
+                std,
+                min,
+                max,
+            } => {
+                let mut value = mean;
+                for _ in 0..Self::MAX_REJECTIONS {
+                    value = mean + std * rng.sample::<f32, _>(StandardNormal);
+                    if (min..=max).contains(&value) {
+                        break;
+                    }
+                }
+                value.clamp(min, max)
+            }
+        }
+    }
+}
+
+/// Distributions of the parameters of random population configurations, which allows biasing them
+/// towards interesting regions, e.g. low sensor angles. Angles are in radians. The default draws
+/// every parameter uniformly from its usual range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigSampler {
+    pub sensor_distance: ParamDistribution,
+    pub step_distance: ParamDistribution,
+    pub decay_factor: ParamDistribution,
+    pub sensor_angle: ParamDistribution,
+    pub rotation_angle: ParamDistribution,
+    pub deposition_amount: ParamDistribution,
+    pub inertia: ParamDistribution,
+    pub speed_jitter: ParamDistribution,
+    pub step_jitter: ParamDistribution,
+    pub deposit_offset: ParamDistribution,
+}
+
+impl ConfigSampler {
+    /// Check the bounds of every distribution.
+    pub fn validate(&self) -> Result<(), Error> {
+        let distributions = [
+            (Param::SensorDistance, &self.sensor_distance),
+            (Param::StepDistance, &self.step_distance),
+            (Param::DecayFactor, &self.decay_factor),
+            (Param::SensorAngle, &self.sensor_angle),
+            (Param::RotationAngle, &self.rotation_angle),
+            (Param::DepositionAmount, &self.deposition_amount),
+            (Param::Inertia, &self.inertia),
+            (Param::SpeedJitter, &self.speed_jitter),
+            (Param::StepJitter, &self.step_jitter),
+            (Param::DepositOffset, &self.deposit_offset),
+        ];
+        for (param, distribution) in distributions.iter() {
+            distribution.validate(*param)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConfigSampler {
+    fn default() -> Self {
+        let uniform = |min, max| ParamDistribution::Uniform { min, max };
+        let angle = |min: f32, max: f32| uniform(min.to_radians(), max.to_radians());
+        type C = PopulationConfig;
+        ConfigSampler {
+            sensor_distance: uniform(C::SENSOR_DISTANCE_MIN, C::SENSOR_DISTANCE_MAX),
+            step_distance: uniform(C::STEP_DISTANCE_MIN, C::STEP_DISTANCE_MAX),
+            decay_factor: uniform(C::DECAY_FACTOR_MIN, C::DECAY_FACTOR_MAX),
+            sensor_angle: angle(C::SENSOR_ANGLE_MIN, C::SENSOR_ANGLE_MAX),
+            rotation_angle: angle(C::ROTATION_ANGLE_MIN, C::ROTATION_ANGLE_MAX),
+            deposition_amount: uniform(C::DEPOSITION_AMOUNT_MIN, C::DEPOSITION_AMOUNT_MAX),
+            inertia: uniform(C::INERTIA_MIN, C::INERTIA_MAX),
+            speed_jitter: uniform(C::SPEED_JITTER_MIN, C::SPEED_JITTER_MAX),
+            step_jitter: uniform(C::STEP_JITTER_MIN, C::STEP_JITTER_MAX),
+            deposit_offset: uniform(C::DEPOSIT_OFFSET_MIN, C::DEPOSIT_OFFSET_MAX),
         }
     }
 }
 
-/// A 2D grid with a scalar value per each grid block. Each grid is occupied by a single population,
-/// hence we store the population config inside the grid.
-#[derive(Debug)]
+/// A 2D grid with a scalar value per each grid block, optionally accompanied by extra chemical
+/// layers. Each grid is occupied by a single population, hence we store the population config
+/// inside the grid.
+#[derive(Debug, Clone)]
 pub struct Grid {
     pub config: PopulationConfig,
     pub width: usize,
     pub height: usize,
 
+    /// Upper bound on the grid values, enforced after deposition and diffusion. It also bounds the
+    /// render white point, which keeps frame brightness from jumping around when trails pile up.
+    pub max_value_cap: Option<f32>,
+
+    // Trail map of this population. Agents deposit into it, and it is diffused and decayed.
     data: Vec<f32>,
 
-    // Scratch space for the blur operation.
-    buf: Vec<f32>,
+    // Extra chemical layers next to the trail map, empty by default.
+    channels: Vec<Channel>,
+
+    // Attraction-weighted mixture of the trail maps of all populations, which is what agents of
+    // this population sense. It is written by `combine` at the start of a step and stays valid
+    // until the next `combine`; nothing else writes to it.
+    sensing: Vec<f32>,
+
+    // Scratch space for the blur operation. Its contents are meaningless outside of `diffuse`.
+    scratch: Vec<f32>,
+
+    // Shape of the domain and, unless it is the whole grid, whether each cell lies inside of it.
+    shape: GridShape,
+    mask: Option<Vec<bool>>,
     blur: Blur,
 }
 
 impl Grid {
     /// Create a new grid filled with random floats in the [0.0..1.0) range.
     pub fn new<R: Rng + ?Sized>(width: usize, height: usize, rng: &mut R) -> Self {
+        Self::sampled(width, height, &ConfigSampler::default(), rng)
+    }
+
+    /// Same as new, but with the population configuration drawn from a sampler.
+    pub fn sampled<R: Rng + ?Sized>(
+        width: usize,
+        height: usize,
+        sampler: &ConfigSampler,
+        rng: &mut R,
+    ) -> Self {
         if !width.is_power_of_two() || !height.is_power_of_two() {
             panic!("Grid dimensions must be a power of two.");
         }
@@ -90,85 +672,709 @@ impl Grid {
         Grid {
             width,
             height,
+            max_value_cap: None,
             data,
-            config: PopulationConfig::new(rng),
-            buf: vec![0.0; width * height],
+            channels: Vec::new(),
+            config: PopulationConfig::sample(sampler, rng),
+            sensing: vec![0.0; width * height],
+            scratch: vec![0.0; width * height],
+            shape: GridShape::Rect,
+            mask: None,
             blur: Blur::new(width),
         }
     }
 
     /// Truncate x and y and return a corresponding index into the data slice.
     fn index(&self, x: f32, y: f32) -> usize {
-        // x/y can come in negative, hence we shift them by width/height.
-        let i = (x + self.width as f32) as usize & (self.width - 1);
-        let j = (y + self.height as f32) as usize & (self.height - 1);
+        // x/y can come in negative, hence we shift them by width/height after flooring.
+        let i = (x.floor() as i32 + self.width as i32) as usize & (self.width - 1);
+        let j = (y.floor() as i32 + self.height as i32) as usize & (self.height - 1);
         j * self.width + i
     }
 
-    /// Get the buffer value at a given position. The implementation effectively treats data as
-    /// periodic, hence any finite position will produce a value.
+    /// Get the trail value of this grid at a given position, with the same periodic indexing as
+    /// get_buf.
+    pub fn get(&self, x: f32, y: f32) -> f32 {
+        self.data[self.index(x, y)]
+    }
+
+    /// Get the value of the combined sensing field at a given position. The implementation
+    /// effectively treats data as periodic, hence any finite position will produce a value.
     pub fn get_buf(&self, x: f32, y: f32) -> f32 {
-        self.buf[self.index(x, y)]
+        self.sensing[self.index(x, y)]
+    }
+
+    /// Get the value of the combined sensing field at a given position, clamping the position to
+    /// the grid instead of wrapping it, so that a sensor beyond an edge reads the edge cell rather
+    /// than the opposite side of the grid. This is the sampler for bounded domains.
+    pub fn get_buf_clamped(&self, x: f32, y: f32) -> f32 {
+        let i = (x.max(0.0) as usize).min(self.width - 1);
+        let j = (y.max(0.0) as usize).min(self.height - 1);
+        self.sensing[j * self.width + i]
+    }
+
+    /// Get the value of the combined sensing field where an agent senses it. The periodic
+    /// rectangle wraps the position like get_buf, while the bounded disk clamps it like
+    /// get_buf_clamped, so agents near its edge don't sense the opposite side of the disk.
+    pub fn sense_at(&self, x: f32, y: f32) -> f32 {
+        match self.shape {
+            GridShape::Rect => self.get_buf(x, y),
+            GridShape::Disk => self.get_buf_clamped(x, y),
+        }
     }
 
-    /// Add a value to the grid data at a given position.
+    /// Gradient of the combined sensing field at a given position, from central differences
+    /// between the neighboring cells, sampled with sense_at.
+    pub fn gradient(&self, x: f32, y: f32) -> (f32, f32) {
+        let dx = self.sense_at(x + 1.0, y) - self.sense_at(x - 1.0, y);
+        let dy = self.sense_at(x, y + 1.0) - self.sense_at(x, y - 1.0);
+        (0.5 * dx, 0.5 * dy)
+    }
+
+    /// Add a value to the grid data at a given position. The amount shrinks as the trail in the
+    /// cell approaches the deposit saturation. Extra channels receive their own fixed amounts.
     pub fn deposit(&mut self, x: f32, y: f32) {
         let idx = self.index(x, y);
-        self.data[idx] += self.config.deposition_amount;
-    }
-
-    /// Diffuse grid data and apply a decay multiplier.
-    pub fn diffuse(&mut self, radius: usize) {
-        self.blur.run(
-            &mut self.data,
-            &mut self.buf,
-            self.width,
-            self.height,
-            radius as f32,
-            self.config.decay_factor,
+        self.deposit_cell(idx);
+    }
+
+    fn deposit_cell(&mut self, idx: usize) {
+        if !self.inside(idx) {
+            return;
+        }
+        let saturation = 1.0 + self.data[idx] / self.config.deposit_saturation;
+        self.data[idx] += self.config.deposition_amount / saturation;
+        for channel in &mut self.channels {
+            channel.data[idx] += channel.config.deposition_amount;
+        }
+    }
+
+    /// Spread an amount over the four cells nearest to a position, weighted by the distance to
+    /// their centers and wrapping around the edges. The weights sum to one, so the total amount is
+    /// conserved. Extra channels are splatted the same way with their own amounts, while the
+    /// deposit saturation is not applied.
+    pub fn deposit_bilinear(&mut self, x: f32, y: f32, amount: f32) {
+        for (idx, weight) in self.splat(x, y) {
+            self.splat_cell(idx, amount, weight);
+        }
+    }
+
+    fn splat_cell(&mut self, idx: usize, amount: f32, weight: f32) {
+        if !self.inside(idx) {
+            return;
+        }
+        self.data[idx] += amount * weight;
+        for channel in &mut self.channels {
+            channel.data[idx] += channel.config.deposition_amount * weight;
+        }
+    }
+
+    /// Deposit the population amount at a batch of positions, each tagged with a unique key such
+    /// as the agent index. Deposits are sorted by cell and key before they accumulate, so the
+    /// result doesn't depend on the order of the batch. Every deposit is stamped with the deposit
+    /// kernel of the population, wrapping around the edges, and with smooth set, every tap of the
+    /// kernel is spread like in deposit_bilinear. The deposit saturation only applies to point
+    /// deposits that aren't smooth. The taps of the kernel are passed in, so that they are built
+    /// once per step rather than on every call.
+    pub fn deposit_batch(
+        &mut self,
+        deposits: &[(f32, f32, usize)],
+        taps: &[(f32, f32, f32)],
+        smooth: bool,
+    ) {
+        use crate::util::wrap;
+        let mut cells: Vec<(usize, usize, f32)> = Vec::with_capacity(deposits.len() * taps.len());
+        for &(x, y, key) in deposits {
+            for &(dx, dy, weight) in taps {
+                let x = wrap(x + dx, self.width as f32);
+                let y = wrap(y + dy, self.height as f32);
+                if smooth {
+                    let splat = self.splat(x, y);
+                    cells.extend(splat.map(|(idx, w)| (idx, key, weight * w)));
+                } else {
+                    cells.push((self.index(x, y), key, weight));
+                }
+            }
+        }
+        // A stable sort keeps the weights of a key that wrap onto the same cell in order.
+        cells.par_sort_by_key(|&(idx, key, _)| (idx, key));
+
+        let amount = self.config.deposition_amount;
+        let point = !smooth && self.config.deposit_kernel == DepositKernel::Point;
+        for (idx, _, weight) in cells {
+            if point {
+                self.deposit_cell(idx);
+            } else {
+                self.splat_cell(idx, amount, weight);
+            }
+        }
+    }
+
+    /// Indices and bilinear weights of the four cells whose centers surround a position.
+    fn splat(&self, x: f32, y: f32) -> [(usize, f32); 4] {
+        // Cell centers sit at half-integer coordinates.
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (wx, wy) = (x - x0, y - y0);
+        let (i0, j0) = (x0 as i32, y0 as i32);
+        let wrap_i = |i: i32| (i + self.width as i32) as usize & (self.width - 1);
+        let wrap_j = |j: i32| (j + self.height as i32) as usize & (self.height - 1);
+        let (i1, j1) = (wrap_i(i0 + 1), wrap_j(j0 + 1));
+        let (i0, j0) = (wrap_i(i0), wrap_j(j0));
+        [
+            (j0 * self.width + i0, (1.0 - wx) * (1.0 - wy)),
+            (j0 * self.width + i1, wx * (1.0 - wy)),
+            (j1 * self.width + i0, (1.0 - wx) * wy),
+            (j1 * self.width + i1, wx * wy),
+        ]
+    }
+
+    /// Take over the configuration, value cap, shape and chemical layers of a grid whose resolution
+    /// differs from this one by the given ratio, scaling the lengths in grid cells. The grid data
+    /// is left alone and the layers start empty.
+    pub fn rescale_settings_from(&mut self, other: &Grid, ratio: f32) {
+        self.config = other.config.scaled(ratio);
+        self.max_value_cap = other.max_value_cap;
+        self.channels = other
+            .channels
+            .iter()
+            .map(|channel| Channel {
+                config: ChannelConfig {
+                    diffusivity: channel.config.diffusivity * ratio,
+                    ..channel.config
+                },
+                data: vec![0.0; self.width * self.height],
+            })
+            .collect();
+        self.set_shape(other.shape);
+    }
+
+    /// Resample the trail map and the extra layers bilinearly to a new size, which must be made of
+    /// powers of two, and scale the lengths in grid cells by the given ratio like
+    /// rescale_settings_from. Values are interpolated rather than redistributed, so trail
+    /// concentrations are kept while the total mass scales with the area.
+    pub(crate) fn rescale(&mut self, width: usize, height: usize, ratio: f32) {
+        let (old_width, old_height) = (self.width, self.height);
+        self.data = resample(&self.data, old_width, old_height, width, height);
+        for channel in &mut self.channels {
+            channel.data = resample(&channel.data, old_width, old_height, width, height);
+            channel.config.diffusivity *= ratio;
+        }
+        self.config = self.config.scaled(ratio);
+        self.width = width;
+        self.height = height;
+        self.sensing = vec![0.0; width * height];
+        self.scratch = vec![0.0; width * height];
+        self.blur = Blur::new(width);
+        self.set_shape(self.shape);
+    }
+
+    /// A copy of the grid bilinearly resampled to a new size, which must be made of powers of two,
+    /// e.g. to render a pattern grown at a low resolution at a higher one. The configuration is
+    /// kept as is, and the sensing field is cleared.
+    pub fn resized(&self, width: usize, height: usize) -> Result<Grid, Error> {
+        if !width.is_power_of_two() || !height.is_power_of_two() {
+            return Err(Error::InvalidGridSize { width, height });
+        }
+        let mut grid = self.clone();
+        grid.rescale(width, height, 1.0);
+        Ok(grid)
+    }
+
+    /// Add an empty chemical layer and return its channel index. Channel 0 is the trail map.
+    pub fn add_channel(&mut self, config: ChannelConfig) -> usize {
+        self.channels.push(Channel {
+            config,
+            data: vec![0.0; self.width * self.height],
+        });
+        self.channels.len()
+    }
+
+    /// Number of chemical layers, including the trail map.
+    pub fn n_channels(&self) -> usize {
+        1 + self.channels.len()
+    }
+
+    /// Values of a chemical layer. Channel 0 is the trail map.
+    pub fn channel(&self, k: usize) -> &[f32] {
+        match k {
+            0 => &self.data,
+            _ => &self.channels[k - 1].data,
+        }
+    }
+
+    /// Diffuse grid data with a Gaussian blur of the given sigma, without any decay. Diffusion is
+    /// split into a number of blur substeps. Variances of consecutive blurs add up, so n substeps
+    /// spread the data like a single blur with sigma scaled by sqrt(n). The extra chemical layers
+    /// are blurred with their own diffusivities.
+    pub fn diffuse(&mut self, sigma: f32, substeps: usize) {
+        self.blur_layers(sigma, substeps, false);
+    }
+
+    /// Multiply the trail by a factor, e.g. to fade it independently of the diffusion.
+    pub fn decay(&mut self, factor: f32) {
+        self.data.par_iter_mut().for_each(|value| *value *= factor);
+    }
+
+    /// Diffuse like diffuse and decay the trail by the decay factor of the population, and the
+    /// extra layers by their own factors, which is what a simulation step does. The decay is folded
+    /// into the last blur pass, so this is cheaper than diffuse followed by decay.
+    pub fn diffuse_and_decay(&mut self, sigma: f32, substeps: usize) {
+        self.blur_layers(sigma, substeps, true);
+    }
+
+    fn blur_layers(&mut self, sigma: f32, substeps: usize, decay: bool) {
+        for k in 0..substeps {
+            let decay = if decay && k + 1 == substeps {
+                self.config.decay_factor
+            } else {
+                1.0
+            };
+            self.blur.run(
+                &mut self.data,
+                &mut self.scratch,
+                self.width,
+                self.height,
+                sigma,
+                decay,
+                BlurBoundary::Periodic,
+            );
+        }
+        self.blur_channels(decay);
+    }
+
+    /// Diffuse grid data like diffuse with a single substep, but perform the blur on a coarser
+    /// grid: the data is downsampled by 2 in each dimension the given number of times, blurred
+    /// there and upsampled back. Only the resampling passes touch the full resolution grid, which
+    /// roughly halves the cost of diffuse. The result is close to the full resolution blur when
+    /// sigma spans several coarse cells, but details finer than the coarse grid are lost. The
+    /// number of levels is capped so that the coarsest grid keeps at least 16 cells along each
+    /// dimension, and zero levels is the same as diffuse.
+    pub fn diffuse_pyramid(&mut self, levels: usize, sigma: f32) {
+        let max_levels = self
+            .width
+            .min(self.height)
+            .trailing_zeros()
+            .saturating_sub(4);
+        let levels = levels.min(max_levels as usize);
+        if levels == 0 {
+            return self.blur_layers(sigma, 1, false);
+        }
+        let (mut width, mut height) = (self.width / 2, self.height / 2);
+        let mut coarse = downsample(&self.data, self.width, self.height);
+        for _ in 1..levels {
+            coarse = downsample(&coarse, width, height);
+            width /= 2;
+            height /= 2;
+        }
+
+        // Box downsampling and bilinear upsampling by a factor f spread the data by a variance of
+        // (f^2 - 1) / 12 and (f^2 - 1) / 4 respectively, which the coarse blur doesn't need to add.
+        let f = (1 << levels) as f32;
+        let variance = (sigma * sigma - (f * f - 1.0) / 3.0).max(0.0);
+        let mut scratch = vec![0.0; width * height];
+        Blur::new(width).run(
+            &mut coarse,
+            &mut scratch,
+            width,
+            height,
+            variance.sqrt() / f,
+            1.0,
+            BlurBoundary::Periodic,
         );
+
+        for _ in 1..levels {
+            let mut fine = vec![0.0; 4 * width * height];
+            upsample(&coarse, width, height, &mut fine);
+            coarse = fine;
+            width *= 2;
+            height *= 2;
+        }
+        upsample(&coarse, width, height, &mut self.data);
+        self.blur_channels(false);
     }
 
+    /// Diffuse the extra chemical layers, decaying them if asked to, then clear everything outside
+    /// of the shape.
+    fn blur_channels(&mut self, decay: bool) {
+        for channel in &mut self.channels {
+            self.blur.run(
+                &mut channel.data,
+                &mut self.scratch,
+                self.width,
+                self.height,
+                channel.config.diffusivity,
+                if decay {
+                    channel.config.decay_factor
+                } else {
+                    1.0
+                },
+                BlurBoundary::Periodic,
+            );
+        }
+        self.apply_mask();
+    }
+
+    pub fn shape(&self) -> GridShape {
+        self.shape
+    }
+
+    /// Confine the grid to a shape. Trail outside of it is cleared and stays zero.
+    pub fn set_shape(&mut self, shape: GridShape) {
+        let (width, height) = (self.width, self.height);
+        self.shape = shape;
+        self.mask = match shape {
+            GridShape::Rect => None,
+            _ => Some(
+                (0..width * height)
+                    .map(|idx| {
+                        let (x, y) = ((idx % width) as f32 + 0.5, (idx / width) as f32 + 0.5);
+                        shape.contains(x, y, width, height)
+                    })
+                    .collect(),
+            ),
+        };
+        self.apply_mask();
+    }
+
+    fn inside(&self, idx: usize) -> bool {
+        self.mask.as_ref().is_none_or(|mask| mask[idx])
+    }
+
+    /// Zero the trail in the cells outside of the grid shape.
+    fn apply_mask(&mut self) {
+        if let Some(mask) = &self.mask {
+            let layers = std::iter::once(&mut self.data)
+                .chain(self.channels.iter_mut().map(|channel| &mut channel.data));
+            for layer in layers {
+                for (value, &inside) in layer.iter_mut().zip(mask) {
+                    if !inside {
+                        *value = 0.0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Overwrite the grid data with the normalized luminance of an image. The image is resampled
+    /// if its dimensions don't match the grid.
+    pub fn seed_from_image(&mut self, img: &GrayImage) {
+        let (width, height) = (self.width as u32, self.height as u32);
+        let resized;
+        let img = if img.dimensions() == (width, height) {
+            img
+        } else {
+            resized = imageops::resize(img, width, height, FilterType::Triangle);
+            &resized
+        };
+        for (value, pixel) in self.data.iter_mut().zip(img.pixels()) {
+            *value = pixel.0[0] as f32 / 255.0;
+        }
+        self.apply_mask();
+    }
+
+    /// Value below which the given fraction of the grid values lie. NaN values are ordered above
+    /// all others, so a grid that has blown up still yields a result instead of a panic.
     pub fn quantile(&self, fraction: f32) -> f32 {
+        self.quantile_with_scratch(fraction, &mut Vec::new())
+    }
+
+    /// Same as quantile, but copies the data into a reusable scratch buffer instead of allocating.
+    pub fn quantile_with_scratch(&self, fraction: f32, scratch: &mut Vec<f32>) -> f32 {
         let index = if (fraction - 1.0_f32).abs() < f32::EPSILON {
             self.data.len() - 1
         } else {
             (self.data.len() as f32 * fraction) as usize
         };
-        let mut sorted = self.data.clone();
-        sorted
+        scratch.clear();
+        scratch.extend_from_slice(&self.data);
+        scratch
             .as_mut_slice()
-            .select_nth_unstable_by(index, |a, b| a.partial_cmp(b).unwrap());
-        sorted[index]
+            .select_nth_unstable_by(index, |a, b| {
+                a.partial_cmp(b)
+                    .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+            });
+        scratch[index]
+    }
+
+    /// Clamp the grid values to the cap, if there is one. NaN values are replaced by the cap.
+    pub fn apply_cap(&mut self) {
+        if let Some(cap) = self.max_value_cap {
+            self.data
+                .iter_mut()
+                .for_each(|value| *value = value.min(cap));
+        }
+    }
+
+    /// Approximate quantile read off a histogram with the given number of bins spanning [0, max]
+    /// of the data. This takes two parallel passes over the data and no sorting, and the result is
+    /// within one bin width of the exact quantile.
+    pub fn quantile_approx(&self, fraction: f32, bins: usize) -> f32 {
+        let max = self.data.par_iter().cloned().reduce(|| 0.0_f32, f32::max);
+        if max <= 0.0 || bins == 0 {
+            return max;
+        }
+        let bin_width = max / bins as f32;
+        let histogram = self
+            .data
+            .par_chunks(self.width)
+            .fold(
+                || vec![0_usize; bins],
+                |mut histogram, row| {
+                    for value in row {
+                        let bin = ((value / bin_width) as usize).min(bins - 1);
+                        histogram[bin] += 1;
+                    }
+                    histogram
+                },
+            )
+            .reduce(
+                || vec![0_usize; bins],
+                |mut a, b| {
+                    a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                    a
+                },
+            );
+
+        let index = ((self.data.len() as f32 * fraction) as usize).min(self.data.len() - 1);
+        let mut count = 0;
+        for (bin, n) in histogram.iter().enumerate() {
+            count += n;
+            if count > index {
+                return (bin + 1) as f32 * bin_width;
+            }
+        }
+        max
+    }
+
+    /// Whether any value of the trail is NaN or infinite.
+    pub fn has_non_finite(&self) -> bool {
+        self.data.par_iter().any(|value| !value.is_finite())
     }
 
     pub fn data(&self) -> &[f32] {
         &self.data
     }
+
+    /// Total trail mass of the grid.
+    pub fn sum(&self) -> f32 {
+        self.data.par_iter().sum()
+    }
+
+    /// Shannon entropy in nats of the trail normalized to a probability distribution over cells.
+    /// It ranges from 0 for a trail concentrated in a single cell to ln(width * height) for a
+    /// uniform one. Negative values are ignored and an empty grid has zero entropy.
+    pub fn entropy(&self) -> f32 {
+        let total: f32 = self.data.par_iter().map(|value| value.max(0.0)).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        self.data
+            .par_iter()
+            .filter(|&&value| value > 0.0)
+            .map(|value| {
+                let p = value / total;
+                -p * p.ln()
+            })
+            .sum()
+    }
+
+    /// Render this grid alone, mapping the tonemapped values onto the palette gradient. The white
+    /// point and gamma are the same as in the blended model render.
+    pub fn heatmap(&self, palette: &Palette) -> RgbImage {
+        let white_point = self.quantile(WHITE_POINT_QUANTILE) * WHITE_POINT_SCALE;
+        let mut img = RgbImage::new(self.width as u32, self.height as u32);
+        for (pixel, value) in img.pixels_mut().zip(&self.data) {
+            *pixel = palette.gradient(render::intensity(*value, white_point));
+        }
+        img
+    }
+
+    /// Write the heatmap of this grid to an image file.
+    #[cfg(feature = "native")]
+    pub fn save_heatmap<P: AsRef<Path>>(
+        &self,
+        path: P,
+        palette: &Palette,
+    ) -> image::ImageResult<()> {
+        self.heatmap(palette).save(path)
+    }
+}
+
+/// Halve the resolution of a grid by averaging blocks of 2x2 cells.
+fn downsample(data: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut coarse = vec![0.0; width * height / 4];
+    coarse
+        .par_chunks_exact_mut(width / 2)
+        .zip(data.par_chunks_exact(2 * width))
+        .for_each(|(coarse_row, rows)| {
+            let (top, bottom) = rows.split_at(width);
+            for (i, value) in coarse_row.iter_mut().enumerate() {
+                *value = 0.25 * (top[2 * i] + top[2 * i + 1] + bottom[2 * i] + bottom[2 * i + 1]);
+            }
+        });
+    coarse
+}
+
+/// Double the resolution of a periodic grid into data by bilinear interpolation between cell
+/// centers. Every fine cell mixes the coarse cell it lies in with weight 3/4 and the closest
+/// neighbor with 1/4 along each dimension.
+fn upsample(coarse: &[f32], width: usize, height: usize, data: &mut [f32]) {
+    data.par_chunks_exact_mut(2 * width)
+        .enumerate()
+        .for_each_init(
+            || vec![0.0; width],
+            |mixed, (j, row)| {
+                let neighbor = if j % 2 == 0 { height - 1 } else { 1 };
+                let (near, far) = (j / 2, (j / 2 + neighbor) % height);
+                let near = &coarse[near * width..(near + 1) * width];
+                let far = &coarse[far * width..(far + 1) * width];
+                for (mixed, near, far) in multizip((mixed.iter_mut(), near, far)) {
+                    *mixed = 0.75 * near + 0.25 * far;
+                }
+                let (first, last) = (mixed[0], mixed[width - 1]);
+                for (k, pair) in row.chunks_exact_mut(2).enumerate() {
+                    let left = if k == 0 { last } else { mixed[k - 1] };
+                    let right = if k + 1 == width { first } else { mixed[k + 1] };
+                    pair[0] = 0.75 * mixed[k] + 0.25 * left;
+                    pair[1] = 0.75 * mixed[k] + 0.25 * right;
+                }
+            },
+        );
+}
+
+/// Resample a periodic grid to a new size by bilinear interpolation between cell centers.
+fn resample(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    new_width: usize,
+    new_height: usize,
+) -> Vec<f32> {
+    let (sx, sy) = (
+        width as f32 / new_width as f32,
+        height as f32 / new_height as f32,
+    );
+    // Position of a new cell center in the cells of the old grid, as the index of the old cell
+    // center at or before it and the weight of the next one.
+    let locate = |k: usize, scale: f32, n: usize| {
+        let t = (k as f32 + 0.5) * scale - 0.5;
+        let floor = t.floor();
+        let k0 = (floor as isize).rem_euclid(n as isize) as usize;
+        (k0, (k0 + 1) % n, t - floor)
+    };
+    let columns: Vec<_> = (0..new_width).map(|i| locate(i, sx, width)).collect();
+    let mut resampled = vec![0.0; new_width * new_height];
+    resampled
+        .par_chunks_exact_mut(new_width)
+        .enumerate()
+        .for_each(|(j, row)| {
+            let (j0, j1, wy) = locate(j, sy, height);
+            let (top, bottom) = (
+                &data[j0 * width..(j0 + 1) * width],
+                &data[j1 * width..(j1 + 1) * width],
+            );
+            for (value, &(i0, i1, wx)) in row.iter_mut().zip(&columns) {
+                let top = top[i0] + (top[i1] - top[i0]) * wx;
+                let bottom = bottom[i0] + (bottom[i1] - bottom[i0]) * wx;
+                *value = top + (bottom - top) * wy;
+            }
+        });
+    resampled
+}
+
+/// Square table of how strongly each population is attracted to the trail of every population, row
+/// i holding the weights that population i senses the others with. Negative weights repel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttractionMatrix(Vec<Vec<f32>>);
+
+impl AttractionMatrix {
+    /// Matrix of n populations that ignore each other, with all weights zero.
+    pub fn new(n: usize) -> Self {
+        AttractionMatrix(vec![vec![0.0; n]; n])
+    }
+
+    /// Matrix with the given rows, which must all be as long as there are rows.
+    pub fn from_rows(rows: Vec<Vec<f32>>) -> Result<Self, Error> {
+        if let Some(row) = rows.iter().find(|row| row.len() != rows.len()) {
+            return Err(Error::NonSquareMatrix {
+                rows: rows.len(),
+                columns: row.len(),
+            });
+        }
+        Ok(AttractionMatrix(rows))
+    }
+
+    /// Number of populations.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Attraction of population i to the trail of population j.
+    pub fn get(&self, i: usize, j: usize) -> f32 {
+        self.0[i][j]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, value: f32) {
+        self.0[i][j] = value;
+    }
+
+    pub fn rows(&self) -> &[Vec<f32>] {
+        &self.0
+    }
+}
+
+impl AsRef<[Vec<f32>]> for AttractionMatrix {
+    fn as_ref(&self) -> &[Vec<f32>] {
+        &self.0
+    }
 }
 
+/// Fill the sensing field of every grid with the sum of the trail maps of all populations, weighted
+/// by the attraction of the grid to each of them, plus its own extra channels. With P populations
+/// this takes up to P² passes over the grid. Pairs with zero attraction are skipped, so a sparse
+/// table only costs as many passes as it has nonzero entries.
 pub fn combine<T>(grids: &mut [Grid], attraction_table: &[T])
 where
     T: AsRef<[f32]> + Sync,
 {
-    let datas: Vec<_> = grids.iter().map(|grid| &grid.data).collect();
-    let bufs: Vec<_> = grids.iter().map(|grid| &grid.buf).collect();
-
-    // We mutate grid buffers and read grid data. We use unsafe because we need shared/unique
-    // borrows on different fields of the same Grid struct.
-    bufs.iter().enumerate().for_each(|(i, buf)| unsafe {
-        let buf_ptr = *buf as *const Vec<f32> as *mut Vec<f32>;
-        buf_ptr.as_mut().unwrap().fill(0.0);
-        datas.iter().enumerate().for_each(|(j, other)| {
-            let multiplier = attraction_table[i].as_ref()[j];
-            buf_ptr
-                .as_mut()
-                .unwrap()
-                .iter_mut()
-                .zip(*other)
-                .for_each(|(to, from)| *to += from * multiplier)
-        })
-    });
+    // We write the sensing fields and read the trail maps and channels, which are disjoint fields
+    // of the grids.
+    let (mut sensings, (datas, channels)): (Vec<_>, (Vec<_>, Vec<_>)) = grids
+        .iter_mut()
+        .map(|grid| (&mut grid.sensing, (&grid.data, &grid.channels)))
+        .unzip();
+
+    sensings
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, sensing)| {
+            sensing.fill(0.0);
+            datas.iter().enumerate().for_each(|(j, other)| {
+                let multiplier = attraction_table[i].as_ref()[j];
+                if multiplier == 0.0 {
+                    return;
+                }
+                sensing
+                    .iter_mut()
+                    .zip(*other)
+                    .for_each(|(to, from)| *to += from * multiplier)
+            });
+            channels[i].iter().for_each(|channel| {
+                let multiplier = channel.config.sensing_weight;
+                if multiplier == 0.0 {
+                    return;
+                }
+                sensing
+                    .iter_mut()
+                    .zip(&channel.data)
+                    .for_each(|(to, from)| *to += from * multiplier)
+            });
+        });
 }
 
 #[cfg(test)]
@@ -192,6 +1398,624 @@ mod tests {
         assert_eq!(grid.index(2.5, 0.6), 2);
         assert_eq!(grid.index(2.5, 1.6), 10);
         assert_eq!(grid.index(7.9, 7.9), 63);
-        assert_eq!(grid.index(-0.5, -0.6), 0);
+        assert_eq!(grid.index(-0.5, -0.6), 63);
+    }
+
+    #[test]
+    fn test_seed_from_image() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 4, &mut rng);
+        let img = GrayImage::from_fn(8, 4, |x, y| image::Luma([(x * 32 + y) as u8]));
+        grid.seed_from_image(&img);
+        assert_eq!(grid.data()[0], 0.0);
+        assert_eq!(grid.data()[8 * 2 + 3], (3 * 32 + 2) as f32 / 255.0);
+
+        let img = GrayImage::from_pixel(32, 32, image::Luma([255]));
+        grid.seed_from_image(&img);
+        assert!(grid.data().iter().all(|&v| (v - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_gradient() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(16, 8, &mut rng);
+        for j in 0..8 {
+            for i in 0..16 {
+                grid.sensing[j * 16 + i] = 2.0 * i as f32 - 0.5 * j as f32;
+            }
+        }
+        for &(x, y) in &[(3.5, 2.5), (7.0, 4.2), (14.9, 6.0)] {
+            assert_eq!(grid.gradient(x, y), (2.0, -0.5));
+        }
+    }
+
+    #[test]
+    fn test_get_buf_clamped() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(16, 8, &mut rng);
+        for (k, value) in grid.sensing.iter_mut().enumerate() {
+            *value = k as f32;
+        }
+        // Inside the grid both samplers agree.
+        assert_eq!(grid.get_buf_clamped(3.5, 2.5), grid.get_buf(3.5, 2.5));
+        // Past the right edge the clamped sampler reads the edge cell, not the left one.
+        assert_eq!(grid.get_buf(17.5, 2.5), grid.sensing[2 * 16 + 1]);
+        assert_eq!(grid.get_buf_clamped(17.5, 2.5), grid.sensing[2 * 16 + 15]);
+        assert_eq!(grid.get_buf_clamped(-3.0, -1.0), grid.sensing[0]);
+        assert_eq!(grid.get_buf_clamped(100.0, 100.0), grid.sensing[8 * 16 - 1]);
+
+        // Agents sense through the sampler of the grid shape.
+        assert_eq!(grid.sense_at(17.5, 2.5), grid.get_buf(17.5, 2.5));
+        grid.set_shape(GridShape::Disk);
+        assert_eq!(grid.sense_at(17.5, 2.5), grid.get_buf_clamped(17.5, 2.5));
+    }
+
+    #[test]
+    fn test_resample() {
+        let (width, height) = (8, 4);
+        let data: Vec<f32> = (0..width * height).map(|k| ((k * 5) % 7) as f32).collect();
+        assert_eq!(resample(&data, width, height, width, height), data);
+
+        // Doubling matches the pyramid upsampling, which interpolates the same way.
+        let mut expected = vec![0.0; 4 * width * height];
+        upsample(&data, width, height, &mut expected);
+        let doubled = resample(&data, width, height, 2 * width, 2 * height);
+        for (v1, v2) in doubled.iter().zip(&expected) {
+            assert!((v1 - v2).abs() < 1e-5);
+        }
+
+        // Halving averages pairs of cells, like downsampling.
+        let halved = resample(&doubled, 2 * width, 2 * height, width, height);
+        let expected = downsample(&doubled, 2 * width, 2 * height);
+        for (v1, v2) in halved.iter().zip(&expected) {
+            assert!((v1 - v2).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_resized() {
+        let mut grid = Grid::new(16, 8, &mut rand::thread_rng());
+        grid.data.iter_mut().for_each(|v| *v = 3.0);
+        assert_eq!(
+            grid.resized(24, 8).err(),
+            Some(Error::InvalidGridSize {
+                width: 24,
+                height: 8
+            })
+        );
+
+        for &(width, height) in &[(64, 32), (8, 4), (32, 2)] {
+            let resized = grid.resized(width, height).unwrap();
+            assert_eq!((resized.width, resized.height), (width, height));
+            assert_eq!(resized.config, grid.config);
+            assert!(resized.data.iter().all(|&v| (v - 3.0).abs() < 1e-6));
+
+            // The buffers follow the new size, so the resized grid can keep evolving.
+            let mut resized = resized;
+            resized.diffuse(1.0, 1);
+            assert!(resized.data.iter().all(|&v| (v - 3.0).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_deposit_kernel() {
+        let mut rng = rand::thread_rng();
+        let deposits = [(0.5, 0.5, 0), (5.2, 3.7, 1), (15.9, 7.1, 2)];
+        for &kernel in &[
+            DepositKernel::Point,
+            DepositKernel::Gaussian3x3,
+            DepositKernel::Disk(0.0),
+            DepositKernel::Disk(2.5),
+        ] {
+            let taps = kernel.taps();
+            let total: f32 = taps.iter().map(|tap| tap.2).sum();
+            assert!((total - 1.0).abs() < 1e-6, "{:?}", kernel);
+
+            // The deposited mass is the same for every kernel, also across the edges.
+            for &smooth in &[false, true] {
+                let mut grid = Grid::new(16, 8, &mut rng);
+                grid.data.fill(0.0);
+                grid.config.deposition_amount = 2.0;
+                grid.config.deposit_kernel = kernel;
+                grid.deposit_batch(&deposits, &taps, smooth);
+                assert!((grid.sum() - 6.0).abs() < 1e-4, "{:?}", kernel);
+            }
+        }
+        assert_eq!(DepositKernel::Disk(1.0).taps().len(), 5);
+        assert_eq!(DepositKernel::Disk(2.5).taps().len(), 21);
+
+        let mut grid = Grid::new(16, 8, &mut rng);
+        grid.data.fill(0.0);
+        grid.config.deposition_amount = 16.0;
+        grid.config.deposit_kernel = DepositKernel::Gaussian3x3;
+        grid.deposit_batch(&[(0.5, 0.5, 0)], &DepositKernel::Gaussian3x3.taps(), false);
+        assert_eq!(grid.data[0], 4.0);
+        assert_eq!(grid.data[1], 2.0);
+        assert_eq!(grid.data[7 * 16 + 15], 1.0);
+        assert_eq!(grid.data[2], 0.0);
+    }
+
+    #[test]
+    fn test_deposit_bilinear() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 4, &mut rng);
+
+        // At a cell center everything lands in that cell.
+        grid.data.fill(0.0);
+        grid.deposit_bilinear(2.5, 1.5, 4.0);
+        assert_eq!(grid.data[grid.index(2.5, 1.5)], 4.0);
+        assert_eq!(grid.data.iter().sum::<f32>(), 4.0);
+
+        // At a corner of the grid the amount is shared by the four corner cells.
+        grid.data.fill(0.0);
+        grid.deposit_bilinear(0.0, 0.0, 4.0);
+        for &(x, y) in &[(0.0, 0.0), (7.0, 0.0), (0.0, 3.0), (7.0, 3.0)] {
+            assert_eq!(grid.data[grid.index(x, y)], 1.0);
+        }
+
+        // The amount is conserved at arbitrary positions.
+        for _ in 0..100 {
+            grid.data.fill(0.0);
+            let (x, y) = (rng.gen_range(-8.0..16.0), rng.gen_range(-4.0..8.0));
+            grid.deposit_bilinear(x, y, 1.0);
+            let weights: f32 = grid.splat(x, y).iter().map(|(_, weight)| weight).sum();
+            assert!((weights - 1.0).abs() <= f32::EPSILON);
+            assert!((grid.data.iter().sum::<f32>() - 1.0).abs() <= f32::EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_deposit_batch() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 8, &mut rng);
+        grid.config.deposit_saturation = 0.3;
+        let mut deposits: Vec<_> = (0..200)
+            .map(|key| (rng.gen_range(0.0..3.0), rng.gen_range(0.0..3.0), key))
+            .collect();
+
+        let taps = DepositKernel::Point.taps();
+        for &smooth in &[false, true] {
+            let data = grid.data.clone();
+            grid.deposit_batch(&deposits, &taps, smooth);
+            let forward = std::mem::replace(&mut grid.data, data);
+            deposits.reverse();
+            grid.deposit_batch(&deposits, &taps, smooth);
+            assert_eq!(grid.data, forward);
+        }
+    }
+
+    #[test]
+    fn test_deposit_saturation() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 8, &mut rng);
+        grid.data.fill(0.0);
+        grid.data[1] = 10.0;
+        grid.config.deposition_amount = 5.0;
+
+        grid.deposit(0.5, 0.5);
+        grid.deposit(1.5, 0.5);
+        assert_eq!(grid.data[0], 5.0);
+        assert_eq!(grid.data[1], 15.0);
+
+        grid.config.deposit_saturation = 10.0;
+        grid.deposit(0.5, 0.5);
+        grid.deposit(1.5, 0.5);
+        assert_eq!(grid.data[0], 5.0 + 5.0 / 1.5);
+        assert_eq!(grid.data[1], 15.0 + 5.0 / 2.5);
+    }
+
+    #[test]
+    fn test_diffuse_substeps() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(32, 32, &mut rng);
+        grid.config.decay_factor = 1.0;
+
+        // Second moment of the data around the grid center.
+        let spread = |grid: &mut Grid, substeps| {
+            grid.data.fill(0.0);
+            grid.data[16 * 32 + 16] = 1.0;
+            grid.diffuse(1.0, substeps);
+            let mut moment = 0.0;
+            for (i, value) in grid.data.iter().enumerate() {
+                let (dx, dy) = (i as f32 % 32.0 - 16.0, (i / 32) as f32 - 16.0);
+                moment += value * (dx * dx + dy * dy);
+            }
+            moment
+        };
+
+        let one = spread(&mut grid, 1);
+        let three = spread(&mut grid, 3);
+        assert!(three > 2.0 * one);
+        assert!((grid.data.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(16, 16, &mut rng);
+        grid.config.decay_factor = 0.5;
+        let initial = grid.data.clone();
+        grid.decay(0.25);
+        for (value, initial) in grid.data.iter().zip(&initial) {
+            assert_eq!(*value, initial * 0.25);
+        }
+
+        // Diffusion alone conserves the mass, and decaying afterwards matches the fused version.
+        let mut fused = grid.clone();
+        let mass = grid.sum();
+        grid.diffuse(1.5, 2);
+        assert!((grid.sum() - mass).abs() < 1e-4 * mass);
+        grid.decay(0.5);
+        fused.diffuse_and_decay(1.5, 2);
+        for (v1, v2) in grid.data.iter().zip(&fused.data) {
+            assert!((v1 - v2).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_diffuse_fractional_sigma() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(16, 16, &mut rng);
+        let initial = grid.data.clone();
+        let mut diffused = |sigma| {
+            grid.data.copy_from_slice(&initial);
+            grid.diffuse(sigma, 1);
+            grid.data.clone()
+        };
+        let (d1, d15, d2) = (diffused(1.0), diffused(1.5), diffused(2.0));
+        assert_ne!(d15, d1);
+        assert_ne!(d15, d2);
+    }
+
+    #[test]
+    fn test_quantile_with_scratch() {
+        let mut rng = rand::thread_rng();
+        let grid = Grid::new(16, 16, &mut rng);
+        let mut scratch = vec![2.0; 3];
+        for &fraction in &[0.0, 0.5, 0.999, 1.0] {
+            let expected = grid.quantile(fraction);
+            assert_eq!(grid.quantile_with_scratch(fraction, &mut scratch), expected);
+        }
+        assert_eq!(scratch.len(), 256);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let valid = [8.0, 1.0, 0.5, 0.5, 0.1, 5.0];
+        let config = |index: usize, value: f32| {
+            let mut p = valid;
+            p[index] = value;
+            PopulationConfig::from_params(p[0], p[1], p[2], p[3], p[4], p[5])
+        };
+        assert!(config(0, valid[0]).is_ok());
+
+        let invalid = [
+            (0, -1.0, Param::SensorDistance),
+            (0, f32::NAN, Param::SensorDistance),
+            (1, -0.5, Param::StepDistance),
+            (2, 200_f32.to_radians(), Param::SensorAngle),
+            (3, -0.1, Param::RotationAngle),
+            (4, 0.0, Param::DecayFactor),
+            (4, 1.5, Param::DecayFactor),
+            (5, 0.0, Param::DepositionAmount),
+        ];
+        for (index, value, param) in invalid.iter() {
+            let err = config(*index, *value).unwrap_err();
+            assert_eq!(err.param, *param);
+        }
+
+        // Parameters beyond the constructor arguments, set like a schedule would.
+        let mut valid = config(0, valid[0]).unwrap();
+        valid.lifecycle = Some(Lifecycle {
+            energy_gain: 1.0,
+            energy_loss: 0.1,
+            split_threshold: 2.0,
+            initial_energy: 1.0,
+        });
+        valid.deposit_kernel = DepositKernel::Disk(2.0);
+        assert_eq!(valid.validate(), Ok(()));
+        let invalid = [
+            (Param::Inertia, 1.0),
+            (Param::Inertia, -0.1),
+            (Param::SpeedJitter, f32::NAN),
+            (Param::SpeedJitter, f32::INFINITY),
+            (Param::StepJitter, -1.0),
+            (Param::DepositSaturation, 0.0),
+            (Param::DepositSaturation, f32::NAN),
+            (Param::DepositOffset, f32::INFINITY),
+            (Param::TurnRandomness, 1.5),
+            (Param::TurnRandomness, -0.1),
+            (Param::TieBreakBias, f32::NAN),
+            (Param::TieBreakBias, 2.0),
+            (Param::DepositRadius, f32::INFINITY),
+            (Param::DepositRadius, -1.0),
+            (Param::DepositRadius, 100.0),
+            (Param::NSensors, 0.0),
+            (Param::NSensors, -2.0),
+            (Param::EnergyGain, -1.0),
+            (Param::EnergyLoss, f32::NAN),
+            (Param::SplitThreshold, 0.0),
+            (Param::InitialEnergy, -1.0),
+        ];
+        for &(param, value) in invalid.iter() {
+            let mut config = valid.clone();
+            config.set(param, value);
+            assert_eq!(config.validate().unwrap_err().param, param);
+        }
+        let mut unsaturated = valid.clone();
+        unsaturated.set(Param::DepositSaturation, f32::INFINITY);
+        assert_eq!(unsaturated.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_quantile_approx() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(64, 64, &mut rng);
+        for (i, value) in grid.data.iter_mut().enumerate() {
+            *value = (*value * 10.0).powi(3) + (i % 7) as f32;
+        }
+        let max = grid.data.iter().cloned().fold(0.0_f32, f32::max);
+        let bins = 256;
+        for &fraction in &[0.0, 0.25, 0.5, 0.9, 0.999, 1.0] {
+            let exact = grid.quantile(fraction);
+            let approx = grid.quantile_approx(fraction, bins);
+            assert!((approx - exact).abs() <= max / bins as f32 * 1.0001);
+        }
+
+        grid.data.fill(0.0);
+        assert_eq!(grid.quantile_approx(0.999, bins), 0.0);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(4, 2, &mut rng);
+        grid.data = vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 100.0];
+        let palette = Palette {
+            colors: [image::Rgb([255, 0, 0]); 5],
+        };
+
+        let img = grid.heatmap(&palette);
+        assert_eq!(img.dimensions(), (4, 2));
+        assert_eq!(img.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+        assert_eq!(img.get_pixel(3, 1), &image::Rgb([255, 0, 0]));
+        let dim = img.get_pixel(2, 1).0[0];
+        assert!(dim > 0 && dim < 255);
+    }
+
+    #[test]
+    fn test_sum_and_entropy() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(4, 4, &mut rng);
+        grid.data.fill(0.5);
+        assert_eq!(grid.sum(), 8.0);
+        assert!((grid.entropy() - 16.0_f32.ln()).abs() < 1e-5);
+
+        grid.data.fill(0.0);
+        assert_eq!(grid.entropy(), 0.0);
+        grid.data[3] = 2.0;
+        assert_eq!(grid.entropy(), 0.0);
+    }
+
+    #[test]
+    fn test_grid_clone() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 8, &mut rng);
+        grid.add_channel(ChannelConfig {
+            deposition_amount: 1.0,
+            decay_factor: 0.5,
+            diffusivity: 1.0,
+            sensing_weight: -1.0,
+        });
+        grid.deposit(2.0, 3.0);
+
+        let mut copy = grid.clone();
+        assert_eq!(copy.config, grid.config);
+        assert_eq!(copy.data, grid.data);
+        assert_eq!(copy.channel(1), grid.channel(1));
+
+        // The copy is independent of the original.
+        copy.deposit(2.0, 3.0);
+        copy.config.decay_factor = 0.5;
+        assert_ne!(copy.data, grid.data);
+        assert_ne!(copy.config, grid.config);
+    }
+
+    #[test]
+    fn test_config_sampler() {
+        let mut rng = rand::thread_rng();
+        let sampler = ConfigSampler {
+            sensor_angle: ParamDistribution::TruncatedNormal {
+                mean: 0.2,
+                std: 0.01,
+                min: 0.0,
+                max: PI,
+            },
+            ..ConfigSampler::default()
+        };
+        for _ in 0..100 {
+            let config = PopulationConfig::sample(&sampler, &mut rng);
+            assert!((config.sensor_angle - 0.2).abs() < 0.1);
+            assert!((0.0..=64.0).contains(&config.sensor_distance));
+            config.validate().unwrap();
+        }
+
+        // A distribution far outside of its bounds is clamped.
+        let narrow = ParamDistribution::TruncatedNormal {
+            mean: 10.0,
+            std: 0.1,
+            min: 0.0,
+            max: 1.0,
+        };
+        assert_eq!(narrow.sample(&mut rng), 1.0);
+
+        let inverted = ConfigSampler {
+            decay_factor: ParamDistribution::Uniform { min: 0.9, max: 0.1 },
+            ..ConfigSampler::default()
+        };
+        assert!(matches!(
+            inverted.validate(),
+            Err(Error::InvalidConfig(ConfigError {
+                param: Param::DecayFactor,
+                ..
+            }))
+        ));
+        assert!(ConfigSampler::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_max_value_cap() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 8, &mut rng);
+        grid.config.decay_factor = 1.0;
+        grid.max_value_cap = Some(3.0);
+        for _ in 0..10 {
+            for _ in 0..5 {
+                grid.deposit(2.5, 4.5);
+            }
+            grid.apply_cap();
+            assert!(grid.data.iter().all(|&value| value <= 3.0));
+            grid.diffuse(1.0, 1);
+            grid.apply_cap();
+            assert!(grid.data.iter().all(|&value| value <= 3.0));
+        }
+    }
+
+    #[test]
+    fn test_quantile_non_finite() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(8, 8, &mut rng);
+        assert!(!grid.has_non_finite());
+        grid.data[3] = f32::NAN;
+        assert!(grid.has_non_finite());
+        grid.data[10] = f32::INFINITY;
+        assert!(grid.quantile(1.0).is_nan());
+        assert_eq!(grid.quantile(0.98), f32::INFINITY);
+        assert!(grid.quantile(0.5) < 1.0);
+        grid.quantile_approx(0.5, 16);
+
+        grid.max_value_cap = Some(2.0);
+        grid.apply_cap();
+        assert!(grid.data.iter().all(|&value| value <= 2.0));
+    }
+
+    #[test]
+    fn test_diffuse_pyramid() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(128, 128, &mut rng);
+        grid.config.decay_factor = 0.9;
+        for k in 0..20 {
+            let (x, y) = ((37 * k % 128) as f32 + 0.3, (71 * k % 128) as f32 + 0.6);
+            grid.deposit_bilinear(x, y, 1000.0);
+        }
+        let mut pyramid = grid.clone();
+        grid.diffuse(12.0, 1);
+        pyramid.diffuse_pyramid(2, 12.0);
+
+        assert!((pyramid.sum() - grid.sum()).abs() < 1e-3 * grid.sum());
+        let max = grid.data.iter().cloned().fold(0.0, f32::max);
+        // Most of the difference comes from rounding the box filter sizes at both resolutions.
+        for (v1, v2) in pyramid.data.iter().zip(&grid.data) {
+            assert!((v1 - v2).abs() < 0.1 * max);
+        }
+
+        // Too many levels are capped at a 16x16 coarse grid.
+        pyramid.diffuse_pyramid(10, 12.0);
+        assert_eq!(pyramid.data.len(), 128 * 128);
+    }
+
+    #[test]
+    fn test_attraction_matrix() {
+        assert_eq!(
+            AttractionMatrix::from_rows(vec![vec![1.0, 0.5], vec![0.5]]),
+            Err(Error::NonSquareMatrix {
+                rows: 2,
+                columns: 1
+            })
+        );
+        assert!(AttractionMatrix::from_rows(vec![vec![1.0, 0.5, 0.0]; 2]).is_err());
+
+        let mut matrix = AttractionMatrix::new(2);
+        assert_eq!(matrix.len(), 2);
+        assert_eq!(matrix.get(1, 0), 0.0);
+        matrix.set(1, 0, -0.5);
+        assert_eq!(matrix.get(1, 0), -0.5);
+        assert_eq!(
+            AttractionMatrix::from_rows(vec![vec![0.0, 0.0], vec![-0.5, 0.0]]),
+            Ok(matrix.clone())
+        );
+
+        // Combining with the matrix is the same as with its rows.
+        let mut rng = rand::thread_rng();
+        let mut grids = vec![Grid::new(8, 8, &mut rng), Grid::new(8, 8, &mut rng)];
+        grids[0].data.fill(2.0);
+        combine(&mut grids, matrix.as_ref());
+        assert!(grids[1].sensing.iter().all(|&value| value == -1.0));
+    }
+
+    #[test]
+    fn test_combine_sparse() {
+        // Zero entries of a sparse table are skipped without changing the result.
+        let mut rng = rand::thread_rng();
+        let mut grids: Vec<_> = (0..3).map(|_| Grid::new(8, 8, &mut rng)).collect();
+        for (n, grid) in grids.iter_mut().enumerate() {
+            for (k, value) in grid.data.iter_mut().enumerate() {
+                *value = ((k * 7 + n * 3) % 11) as f32;
+            }
+        }
+        let sparse_table = [[0.0, 2.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.5]];
+        combine(&mut grids, &sparse_table);
+        for (i, grid) in grids.iter().enumerate() {
+            for (k, value) in grid.sensing.iter().enumerate() {
+                let expected: f32 = (0..3).map(|j| grids[j].data[k] * sparse_table[i][j]).sum();
+                assert_eq!(*value, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_combine() {
+        let mut rng = rand::thread_rng();
+        let mut grids = vec![Grid::new(8, 8, &mut rng), Grid::new(8, 8, &mut rng)];
+        let attraction_table = [[1.1, -0.9], [-1.05, 0.95]];
+        combine(&mut grids, &attraction_table);
+
+        for (i, grid) in grids.iter().enumerate() {
+            for (k, value) in grid.sensing.iter().enumerate() {
+                let mut expected = 0.0;
+                for (j, other) in grids.iter().enumerate() {
+                    expected += other.data[k] * attraction_table[i][j];
+                }
+                assert_eq!(*value, expected);
+            }
+        }
+
+        // Extra channels are added to the sensing field of their own grid only.
+        let repellent = ChannelConfig {
+            deposition_amount: 2.0,
+            decay_factor: 0.5,
+            diffusivity: 1.0,
+            sensing_weight: -3.0,
+        };
+        assert_eq!(grids[1].add_channel(repellent), 1);
+        assert_eq!(grids[1].n_channels(), 2);
+        grids[1].deposit(3.0, 4.0);
+        let idx = grids[1].index(3.0, 4.0);
+        assert_eq!(grids[1].channel(1)[idx], 2.0);
+        combine(&mut grids, &attraction_table);
+        let expected = grids[1].data[idx] * 0.95 + grids[0].data[idx] * -1.05 - 6.0;
+        assert!((grids[1].sensing[idx] - expected).abs() < 1e-5);
+        let expected = grids[0].data[idx] * 1.1 + grids[1].data[idx] * -0.9;
+        assert_eq!(grids[0].sensing[idx], expected);
+
+        // Channels diffuse with their own decay, conserving the decayed mass.
+        grids[1].diffuse_and_decay(1.0, 1);
+        let mass: f32 = grids[1].channel(1).iter().sum();
+        assert!((mass - 1.0).abs() < 1e-5);
+
+        // Diffusion leaves the sensing field alone.
+        let sensing = grids[0].sensing.clone();
+        grids[0].diffuse(1.0, 2);
+        assert_eq!(grids[0].sensing, sensing);
     }
 }
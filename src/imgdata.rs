@@ -0,0 +1,318 @@
+use crate::error::Error;
+
+#[cfg(feature = "native")]
+use image::{codecs::jpeg::JpegEncoder, ColorType, ImageResult};
+use image::{ImageFormat, RgbImage};
+#[cfg(feature = "native")]
+use rayon::prelude::*;
+
+use std::io::Write;
+#[cfg(feature = "native")]
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+/// Format in which frames are written, with the quality used by lossy encoders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub format: ImageFormat,
+    /// Quality from 1 to 100, only used for JPEG.
+    pub quality: u8,
+}
+
+impl Default for FrameFormat {
+    fn default() -> Self {
+        FrameFormat {
+            format: ImageFormat::Png,
+            quality: 90,
+        }
+    }
+}
+
+impl FrameFormat {
+    /// JPEG with the given quality, which must be in 1..=100.
+    pub fn jpeg(quality: u8) -> Result<Self, Error> {
+        let format = FrameFormat {
+            format: ImageFormat::Jpeg,
+            quality,
+        };
+        format.validate()?;
+        Ok(format)
+    }
+
+    /// Check that the format can be written and that the quality is in 1..=100.
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.format.can_write() {
+            return Err(Error::UnsupportedFormat(format!("{:?}", self.format)));
+        }
+        if !(1..=100).contains(&self.quality) {
+            return Err(Error::InvalidQuality(self.quality));
+        }
+        Ok(())
+    }
+
+    /// Extension of the files written in this format, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        self.format.extensions_str()[0]
+    }
+
+    /// File name of a frame. Frame numbers are zero-padded so that names sort in frame order.
+    pub fn file_name(&self, frame: usize) -> String {
+        format!("out_{:08}.{}", frame, self.extension())
+    }
+
+    /// Encode row-major pixels to a file in this format, regardless of the extension of the path.
+    #[cfg(feature = "native")]
+    pub fn save_buffer<P: AsRef<Path>>(
+        &self,
+        path: P,
+        pixels: &[u8],
+        width: u32,
+        height: u32,
+        color: ColorType,
+    ) -> ImageResult<()> {
+        match self.format {
+            ImageFormat::Jpeg => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                JpegEncoder::new_with_quality(&mut writer, self.quality)
+                    .encode(pixels, width, height, color)?;
+                writer.flush()?;
+                Ok(())
+            }
+            format => image::save_buffer_with_format(path, pixels, width, height, color, format),
+        }
+    }
+}
+
+/// A captured frame: the tonemapped RGB pixels of the model at a given iteration. Frames carry no
+/// alpha, whatever Model::set_output_alpha says.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImgData {
+    pub width: u32,
+    pub height: u32,
+    pub iteration: usize,
+    /// Position of the frame in the sequence of all frames captured by the model.
+    pub frame: usize,
+    /// Row-major RGB pixels, three bytes per pixel.
+    pub pixels: Vec<u8>,
+}
+
+impl ImgData {
+    /// Memory taken by the pixels in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.pixels.len()
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        RgbImage::from_raw(self.width, self.height, self.pixels.clone()).unwrap()
+    }
+
+    /// File name of the frame as a PNG image.
+    pub fn file_name(frame: usize) -> String {
+        FrameFormat::default().file_name(frame)
+    }
+
+    /// Write the frame to the given directory as a PNG image.
+    #[cfg(feature = "native")]
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> ImageResult<()> {
+        self.save_as(dir, &FrameFormat::default())
+    }
+
+    /// Write the frame to the given directory in the given format.
+    #[cfg(feature = "native")]
+    pub fn save_as<P: AsRef<Path>>(&self, dir: P, format: &FrameFormat) -> ImageResult<()> {
+        let path = dir.as_ref().join(format.file_name(self.frame));
+        format.save_buffer(path, &self.pixels, self.width, self.height, ColorType::Rgb8)
+    }
+
+    /// Write the frame as a binary PPM (P6) image, which needs nothing beyond the standard
+    /// library.
+    pub fn write_ppm<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.width, self.height)?;
+        w.write_all(&self.pixels)
+    }
+}
+
+/// Write all frames to the given directory in parallel as PNG images.
+#[cfg(feature = "native")]
+pub fn save_all<P: AsRef<Path> + Sync>(frames: &[ImgData], dir: P) -> ImageResult<()> {
+    save_all_as(frames, dir, &FrameFormat::default())
+}
+
+/// Write all frames to the given directory in parallel in the given format.
+#[cfg(feature = "native")]
+pub fn save_all_as<P: AsRef<Path> + Sync>(
+    frames: &[ImgData],
+    dir: P,
+    format: &FrameFormat,
+) -> ImageResult<()> {
+    frames
+        .par_iter()
+        .try_for_each(|frame| frame.save_as(&dir, format))
+}
+
+/// Background thread that encodes and writes frames to a directory while the caller goes on, e.g.
+/// with the next simulation step. Frames are handed over through a bounded queue: once it holds
+/// capacity frames, send blocks until the worker catches up, which bounds the memory taken by
+/// pending frames.
+#[cfg(feature = "native")]
+pub struct FrameWriter {
+    sender: Option<SyncSender<ImgData>>,
+    worker: Option<JoinHandle<ImageResult<()>>>,
+}
+
+#[cfg(feature = "native")]
+impl FrameWriter {
+    /// Start a worker writing frames to the given directory in the given format, with room for
+    /// capacity pending frames.
+    pub fn new(dir: PathBuf, format: FrameFormat, capacity: usize) -> Self {
+        let (sender, receiver) = sync_channel::<ImgData>(capacity);
+        let worker = thread::spawn(move || {
+            receiver
+                .into_iter()
+                .try_for_each(|frame| frame.save_as(&dir, &format))
+        });
+        FrameWriter {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Queue a frame for writing, blocking while the queue is full. If the worker stopped on an
+    /// error, that error is returned instead.
+    pub fn send(&mut self, frame: ImgData) -> Result<(), Error> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(frame).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            self.join()
+        }
+    }
+
+    /// Wait until all queued frames are written and stop the worker.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        // Closing the queue ends the loop of the worker once it has drained the queue.
+        self.sender = None;
+        match self.worker.take() {
+            Some(worker) => match worker.join() {
+                Ok(result) => Ok(result?),
+                Err(_) => Err(Error::Io("the frame writer panicked".to_string())),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_ppm() {
+        let frame = ImgData {
+            width: 4,
+            height: 3,
+            iteration: 0,
+            frame: 0,
+            pixels: (0..36).collect(),
+        };
+        let mut ppm = Vec::new();
+        frame.write_ppm(&mut ppm).unwrap();
+
+        let header = b"P6\n4 3\n255\n";
+        assert!(ppm.starts_with(header));
+        assert_eq!(ppm.len(), 4 * 3 * 3 + header.len());
+        assert_eq!(&ppm[header.len()..], &frame.pixels[..]);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_save_as() {
+        let dir = std::env::temp_dir().join(format!("physarum_format_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let frame = ImgData {
+            width: 16,
+            height: 16,
+            iteration: 0,
+            frame: 3,
+            pixels: (0..16 * 16 * 3).map(|k| (k % 256) as u8).collect(),
+        };
+
+        assert_eq!(FrameFormat::jpeg(0), Err(Error::InvalidQuality(0)));
+        assert_eq!(FrameFormat::jpeg(101), Err(Error::InvalidQuality(101)));
+        let webp = FrameFormat {
+            format: ImageFormat::WebP,
+            ..FrameFormat::default()
+        };
+        assert!(matches!(webp.validate(), Err(Error::UnsupportedFormat(_))));
+
+        let sizes: Vec<u64> = [1, 95]
+            .iter()
+            .map(|&quality| {
+                let format = FrameFormat::jpeg(quality).unwrap();
+                frame.save_as(&dir, &format).unwrap();
+                let path = dir.join("out_00000003.jpg");
+                let saved = image::open(&path).unwrap();
+                assert_eq!(saved.to_rgb8().dimensions(), (16, 16));
+                std::fs::metadata(path).unwrap().len()
+            })
+            .collect();
+        assert!(sizes[0] < sizes[1]);
+
+        frame.save(&dir).unwrap();
+        let saved = image::open(dir.join("out_00000003.png")).unwrap();
+        assert_eq!(saved.to_rgb8(), frame.to_image());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_frame_writer() {
+        let dir = std::env::temp_dir().join(format!("physarum_writer_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A queue of a single frame makes the sender wait for the worker most of the time.
+        let mut writer = FrameWriter::new(dir.clone(), FrameFormat::default(), 1);
+        let frames: Vec<_> = (0..10)
+            .map(|frame| ImgData {
+                width: 8,
+                height: 4,
+                iteration: frame,
+                frame,
+                pixels: vec![(25 * frame) as u8; 8 * 4 * 3],
+            })
+            .collect();
+        for frame in &frames {
+            writer.send(frame.clone()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        for frame in &frames {
+            let saved = image::open(dir.join(ImgData::file_name(frame.frame))).unwrap();
+            assert_eq!(saved.to_rgb8(), frame.to_image());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Errors of the worker surface on the next send or when finishing.
+        let mut writer = FrameWriter::new(dir.join("missing"), FrameFormat::default(), 1);
+        let failed = (0..3).any(|_| writer.send(frames[0].clone()).is_err());
+        assert!(failed || writer.finish().is_err());
+    }
+}
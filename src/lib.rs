@@ -1,6 +1,38 @@
-mod blur;
-mod grid;
+//! Physarum polycephalum slime mold simulation.
+//!
+//! [`model::Model`] runs the simulation and renders it, and is usually constructed with
+//! [`model::ModelBuilder`]. Each population lives on a [`grid::Grid`] governed by a
+//! [`grid::PopulationConfig`]. The remaining modules hold the building blocks: the diffusion blur,
+//! the color palettes, frame buffers and rendering helpers, and fast trigonometry. The most
+//! commonly used types are re-exported by the [`prelude`].
+//!
+//! The default native feature runs the simulation on rayon threads and writes image files.
+//! Without it the crate is pure computation on the calling thread, which builds for
+//! wasm32-unknown-unknown, and the wasm feature adds JavaScript bindings on top.
+#![cfg_attr(feature = "simd", feature(portable_simd))]
+
+pub mod blur;
+pub mod error;
+pub mod grid;
+pub mod imgdata;
 pub mod model;
-mod palette;
-pub mod trig; // for benchmarking
-mod util;
+pub mod palette;
+mod parallel;
+pub mod render;
+pub mod spatial;
+pub mod trig;
+pub mod util;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Glob import of the commonly used types: `use physarum::prelude::*;`.
+pub mod prelude {
+    pub use crate::{
+        blur::Blur,
+        error::Error,
+        grid::{Grid, PopulationConfig},
+        imgdata::ImgData,
+        model::{InitialPlacement, Model, ModelBuilder, RunConfig, RunStats},
+        palette::Palette,
+    };
+}
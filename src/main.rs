@@ -7,7 +7,7 @@ fn main() {
     let n_iterations = 400;
     let (width, height) = (1024, 1024);
     let n_particles = 1 << 22;
-    let diffusivity = 1;
+    let diffusivity = 1.0;
     let mut rng = rand::thread_rng();
 
     loop {
@@ -21,7 +21,8 @@ fn main() {
         );
 
         let n_populations = 1 + rng.gen_range(1..4);
-        let mut model = model::Model::new(width, height, n_particles, n_populations, diffusivity);
+        let mut model =
+            model::Model::new(width, height, n_particles, n_populations, diffusivity).unwrap();
         model.print_configurations();
 
         for i in 0..n_iterations {
@@ -31,6 +32,7 @@ fn main() {
         pb.finish();
 
         let now: DateTime<Utc> = Utc::now();
-        model.save_to_image(format!("out_{}.png", now.timestamp()).as_str());
+        let extension = model.frame_format().extension();
+        model.save_to_image(format!("out_{}.{}", now.timestamp(), extension).as_str());
     }
 }
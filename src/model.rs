@@ -1,54 +1,527 @@
 use crate::{
-    grid::{combine, Grid, PopulationConfig},
-    palette::{random_palette, Palette},
+    blur::Blur,
+    error::{ConfigError, Error},
+    grid::{
+        combine, AttractionMatrix, ChannelConfig, ConfigSampler, Grid, GridShape, Lifecycle, Param,
+        PopulationConfig, Steering,
+    },
+    imgdata::{FrameFormat, ImgData},
+    palette::{random_palette_seeded, Palette},
+    parallel::prelude::*,
+    render::{self, PixelFormat, TonemapConfig, WHITE_POINT_QUANTILE, WHITE_POINT_SCALE},
+    spatial::SpatialHash,
+    util::{agent_rng, wrap_angle},
 };
 
-use rand::{seq::SliceRandom, Rng};
-use rand_distr::{Distribution, Normal};
-use rayon::prelude::*;
+#[cfg(feature = "native")]
+use crate::imgdata::{self, FrameWriter};
+#[cfg(feature = "native")]
+use image::ColorType;
+use image::{imageops, imageops::FilterType, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rand_distr::{Distribution, Normal, StandardNormal};
+#[cfg(feature = "native")]
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
 use itertools::multizip;
-use std::f32::consts::TAU;
+use std::{
+    borrow::Cow,
+    f32::consts::{PI, TAU},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+#[cfg(feature = "native")]
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// Initial spatial distribution of agents. The same placement is applied to every population.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InitialPlacement {
+    /// Scatter agents uniformly at random across the whole grid.
+    #[default]
+    Uniform,
+    /// Fill a disk of radius r centered at (cx, cy) uniformly, with agents heading outwards.
+    Disk { cx: f32, cy: f32, r: f32 },
+    /// Place agents on a circle of radius r centered at (cx, cy), heading towards its center.
+    Ring { cx: f32, cy: f32, r: f32 },
+    /// Place agents on a regular lattice covering the whole grid, with random headings.
+    Grid,
+}
+
+impl InitialPlacement {
+    /// The same placement on a grid scaled by the given ratio.
+    fn scaled(&self, ratio: f32) -> Self {
+        match *self {
+            InitialPlacement::Disk { cx, cy, r } => InitialPlacement::Disk {
+                cx: cx * ratio,
+                cy: cy * ratio,
+                r: r * ratio,
+            },
+            InitialPlacement::Ring { cx, cy, r } => InitialPlacement::Ring {
+                cx: cx * ratio,
+                cy: cy * ratio,
+                r: r * ratio,
+            },
+            placement => placement,
+        }
+    }
+
+    /// Sample the position and the heading of the k-th out of n agents of a population.
+    fn sample<R: Rng + ?Sized>(
+        &self,
+        k: usize,
+        n: usize,
+        width: usize,
+        height: usize,
+        rng: &mut R,
+    ) -> (f32, f32, f32) {
+        use crate::util::wrap;
+        let (width, height) = (width as f32, height as f32);
+        match *self {
+            InitialPlacement::Uniform => {
+                let (x, y, angle) = rng.gen::<(f32, f32, f32)>();
+                (x * width, y * height, angle * TAU)
+            }
+            InitialPlacement::Disk { cx, cy, r } => {
+                let (u, phi) = rng.gen::<(f32, f32)>();
+                let (rho, phi) = (r * u.sqrt(), phi * TAU);
+                let x = wrap(cx + rho * phi.cos(), width);
+                let y = wrap(cy + rho * phi.sin(), height);
+                (x, y, phi)
+            }
+            InitialPlacement::Ring { cx, cy, r } => {
+                let phi = rng.gen::<f32>() * TAU;
+                let x = wrap(cx + r * phi.cos(), width);
+                let y = wrap(cy + r * phi.sin(), height);
+                (x, y, wrap_angle(phi + PI))
+            }
+            InitialPlacement::Grid => {
+                let cols = (n as f32).sqrt().ceil().max(1.0);
+                let rows = (n as f32 / cols).ceil().max(1.0);
+                let (i, j) = ((k as f32 % cols).floor(), (k as f32 / cols).floor());
+                let x = (i + 0.5) * width / cols;
+                let y = (j + 0.5) * height / rows;
+                (x, y, rng.gen::<f32>() * TAU)
+            }
+        }
+    }
+}
 
 /// A single Physarum agent. The x and y positions are continuous, hence we use floating point
 /// numbers instead of integers.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 struct Agent {
+    // Unique agent index.
+    i: usize,
     x: f32,
     y: f32,
     angle: f32,
+    angular_velocity: f32,
+    // Multiplier of the population step distance.
+    speed: f32,
+    // Only used by populations with a lifecycle.
+    energy: f32,
     population_id: usize,
+    // Strongest trail sensed in the last move, infinite before the first one.
+    sensed: f32,
 }
 
 impl Agent {
-    /// Construct a new agent with random parameters.
-    fn new<R: Rng + ?Sized>(width: usize, height: usize, id: usize, rng: &mut R) -> Self {
-        let (x, y, angle) = rng.gen::<(f32, f32, f32)>();
+    /// Construct a new agent at a given position and heading.
+    fn new(i: usize, x: f32, y: f32, angle: f32, speed: f32, id: usize) -> Self {
         Agent {
-            x: x * width as f32,
-            y: y * height as f32,
-            angle: angle * TAU,
+            i,
+            x,
+            y,
+            angle,
+            angular_velocity: 0.0,
+            speed,
+            energy: 0.0,
             population_id: id,
+            sensed: f32::INFINITY,
         }
     }
 
-    /// Update agent's orientation angle and position on the grid.
+    /// Update agent's orientation angle and position on the grid. With non-zero inertia the agent
+    /// approaches the desired turn gradually by accumulating angular velocity.
     fn rotate_and_move(
         &mut self,
         direction: f32,
         rotation_angle: f32,
+        inertia: f32,
         step_distance: f32,
         width: usize,
         height: usize,
     ) {
         use crate::util::wrap;
         let delta_angle = rotation_angle * direction;
-        self.angle = wrap(self.angle + delta_angle, TAU);
+        self.angular_velocity = inertia * self.angular_velocity + (1.0 - inertia) * delta_angle;
+        self.angle = wrap_angle(self.angle + self.angular_velocity);
+        let step_distance = step_distance * self.speed;
         self.x = wrap(self.x + step_distance * self.angle.cos(), width as f32);
         self.y = wrap(self.y + step_distance * self.angle.sin(), height as f32);
     }
+
+    /// Undo a move that left the disk centered at (cx, cy) and reflect the heading off the disk
+    /// boundary, as if the agent bounced off a wall.
+    fn bounce(&mut self, from: (f32, f32), cx: f32, cy: f32) {
+        let (nx, ny) = (self.x - cx, self.y - cy);
+        let norm = (nx * nx + ny * ny).sqrt();
+        let (nx, ny) = (nx / norm, ny / norm);
+        let (dx, dy) = (self.angle.cos(), self.angle.sin());
+        let dot = dx * nx + dy * ny;
+        let (dx, dy) = (dx - 2.0 * dot * nx, dy - 2.0 * dot * ny);
+        self.angle = wrap_angle(dy.atan2(dx));
+        self.x = from.0;
+        self.y = from.1;
+    }
+
+    /// Position at a given distance behind the agent.
+    fn deposit_position(&self, offset: f32) -> (f32, f32) {
+        (
+            self.x - self.angle.cos() * offset,
+            self.y - self.angle.sin() * offset,
+        )
+    }
+}
+
+/// Read-only view of an agent and the trail maps around it, passed to population reassignment.
+#[derive(Debug, Clone, Copy)]
+pub struct AgentView<'a> {
+    pub x: f32,
+    pub y: f32,
+    pub angle: f32,
+    pub energy: f32,
+    pub population_id: usize,
+    grids: &'a [Grid],
+}
+
+impl AgentView<'_> {
+    /// Trail of a population at the agent position.
+    pub fn trail(&self, population_id: usize) -> f32 {
+        self.grids[population_id].get(self.x, self.y)
+    }
+}
+
+/// Builder for a Model. Every parameter has a default, so only the ones of interest need to be set.
+#[derive(Debug, Clone)]
+pub struct ModelBuilder {
+    width: usize,
+    height: usize,
+    n_particles: usize,
+    n_populations: usize,
+    diffusivity: f32,
+    placement: InitialPlacement,
+    shape: GridShape,
+    seed: Option<u64>,
+    palette: Option<Palette>,
+    configs: Option<Vec<PopulationConfig>>,
+    sampler: ConfigSampler,
+}
+
+impl Default for ModelBuilder {
+    fn default() -> Self {
+        ModelBuilder {
+            width: 1024,
+            height: 1024,
+            n_particles: 1 << 22,
+            n_populations: 1,
+            diffusivity: 1.0,
+            placement: InitialPlacement::default(),
+            shape: GridShape::default(),
+            seed: None,
+            palette: None,
+            configs: None,
+            sampler: ConfigSampler::default(),
+        }
+    }
+}
+
+impl ModelBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the grid dimensions. Both must be powers of two.
+    pub fn size(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Set the total number of agents, split evenly across populations.
+    pub fn particles(mut self, n_particles: usize) -> Self {
+        self.n_particles = n_particles;
+        self
+    }
+
+    pub fn populations(mut self, n_populations: usize) -> Self {
+        self.n_populations = n_populations;
+        self
+    }
+
+    /// Set the standard deviation of the Gaussian blur that diffuses the trail every step. See
+    /// Blur::effective_radius for the spread it gives, values below about 0.58 do nothing and are
+    /// rejected by build.
+    pub fn diffusivity(mut self, diffusivity: f32) -> Self {
+        self.diffusivity = diffusivity;
+        self
+    }
+
+    pub fn placement(mut self, placement: InitialPlacement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Confine the agents and trails of all populations to a shape. Agents placed outside of it
+    /// are moved to random positions inside.
+    pub fn shape(mut self, shape: GridShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    /// Seed the random number generator used for the initial conditions and configurations. A
+    /// random seed is used if none is given.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Use the given palette instead of a random one.
+    pub fn palette(mut self, palette: Palette) -> Self {
+        self.palette = Some(palette);
+        self
+    }
+
+    /// Draw the random population configurations from the given distributions.
+    pub fn sampler(mut self, sampler: ConfigSampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    fn n_populations(&self) -> usize {
+        self.configs.as_ref().map_or(self.n_populations, Vec::len)
+    }
+
+    /// Use the given population configurations instead of random ones, one population per
+    /// configuration. This overrides the number of populations.
+    pub fn configs(mut self, configs: Vec<PopulationConfig>) -> Self {
+        self.configs = Some(configs);
+        self
+    }
+
+    /// Validate the parameters and construct the model.
+    pub fn build(&self) -> Result<Model, Error> {
+        if !self.width.is_power_of_two() || !self.height.is_power_of_two() {
+            return Err(Error::InvalidGridSize {
+                width: self.width,
+                height: self.height,
+            });
+        }
+        if self.n_particles == 0 {
+            return Err(Error::ZeroCount("particles"));
+        }
+        if self.n_populations() == 0 {
+            return Err(Error::ZeroCount("populations"));
+        }
+        Model::check_diffusivity(self.diffusivity)?;
+        match &self.configs {
+            Some(configs) => {
+                for config in configs {
+                    config.validate()?;
+                }
+            }
+            None => self.sampler.validate()?,
+        }
+        let seed = self.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        Model::from_builder(self, seed)
+    }
+}
+
+/// Timing statistics of a simulation run.
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    /// Wall-clock time of every step in milliseconds.
+    pub per_step_ms: Vec<f64>,
+    /// Time of every step divided by the number of agents, in milliseconds. Steps of a model
+    /// without agents count as zero.
+    pub per_agent_ms: Vec<f64>,
+    /// Total wall-clock time of the run in milliseconds.
+    pub total_ms: f64,
+    /// Number of leading warmup steps, which are included in the timings above.
+    pub warmup_steps: usize,
+}
+
+impl RunStats {
+    /// Time a single step of the model.
+    fn record_step(&mut self, model: &mut Model) {
+        let step_start = Instant::now();
+        model.step();
+        let step_ms = step_start.elapsed().as_secs_f64() * 1e3;
+        self.per_step_ms.push(step_ms);
+        let n_agents = model.agents.len();
+        self.per_agent_ms.push(if n_agents > 0 {
+            step_ms / n_agents as f64
+        } else {
+            0.0
+        });
+    }
+
+    /// Average step time in milliseconds, excluding the warmup steps.
+    pub fn mean_step_ms(&self) -> f64 {
+        mean(&self.per_step_ms[self.warmup_steps.min(self.per_step_ms.len())..])
+    }
+
+    /// Average step time per agent in milliseconds, excluding the warmup steps.
+    pub fn mean_agent_ms(&self) -> f64 {
+        mean(&self.per_agent_ms[self.warmup_steps.min(self.per_agent_ms.len())..])
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+/// Trail mass and entropy of every population, sampled at the captured steps of a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimeSeries {
+    /// Model iteration of every sample.
+    pub iterations: Vec<usize>,
+    /// Total trail mass, indexed by population and then by sample.
+    pub mass: Vec<Vec<f32>>,
+    /// Entropy of the normalized trail, see Grid::entropy, indexed like mass.
+    pub entropy: Vec<Vec<f32>>,
+}
+
+impl TimeSeries {
+    #[cfg(feature = "native")]
+    fn record(&mut self, model: &Model) {
+        self.mass.resize(model.grids.len(), Vec::new());
+        self.entropy.resize(model.grids.len(), Vec::new());
+        self.iterations.push(model.iteration);
+        for (i, grid) in model.grids.iter().enumerate() {
+            self.mass[i].push(grid.sum());
+            self.entropy[i].push(grid.entropy());
+        }
+    }
+
+    /// Format the series as CSV with a header and one row per sample. The columns are the
+    /// iteration followed by the mass and entropy of every population.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("iteration");
+        for i in 0..self.mass.len() {
+            csv += &format!(",mass_{},entropy_{}", i, i);
+        }
+        csv.push('\n');
+        for (k, iteration) in self.iterations.iter().enumerate() {
+            csv += &iteration.to_string();
+            for (mass, entropy) in self.mass.iter().zip(&self.entropy) {
+                csv += &format!(",{},{}", mass[k], entropy[k]);
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn save_csv<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.to_csv())?;
+        Ok(())
+    }
+}
+
+/// A phase of a simulation step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Mix the trail maps of all populations into the fields the agents sense.
+    Combine,
+    /// Sense, turn and move the agents, then let them reproduce and die.
+    Move,
+    /// Drop the trail of every agent onto the grid of its population.
+    Deposit,
+    /// Blur and decay the trail maps.
+    Diffuse,
+}
+
+/// Order in which the phases run within every step. The default order is combine, move, deposit
+/// and diffuse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepOrder([Phase; 4]);
+
+impl Default for StepOrder {
+    fn default() -> Self {
+        StepOrder([Phase::Combine, Phase::Move, Phase::Deposit, Phase::Diffuse])
+    }
+}
+
+impl StepOrder {
+    /// Build a step order from a permutation of all the phases.
+    pub fn new(phases: [Phase; 4]) -> Result<Self, Error> {
+        let default = StepOrder::default();
+        if default.0.iter().any(|phase| !phases.contains(phase)) {
+            return Err(Error::InvalidStepOrder);
+        }
+        Ok(StepOrder(phases))
+    }
+
+    pub fn phases(&self) -> &[Phase] {
+        &self.0
+    }
+}
+
+/// Settings of a simulation run that captures frames to disk.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    /// Number of steps to perform.
+    pub steps: usize,
+    /// Capture a frame every so many steps after the warmup. Zero captures only the final frame.
+    pub capture_every: usize,
+    /// Number of leading steps that are simulated but never captured. Frame numbers start at the
+    /// warmup, so the first frame after a warmup of 300 steps is `out_00000300`, unless earlier
+    /// frames were already numbered past it. See also Model::set_warmup.
+    pub warmup: usize,
+    /// Directory the frames are written to.
+    pub output_dir: PathBuf,
+    /// Keep captured frames in memory and encode them in parallel at the end of the run. Otherwise
+    /// every frame is written as soon as it is captured, which keeps memory usage flat.
+    pub buffer_frames: bool,
+    /// Encode and write frames on a background thread while the simulation goes on, instead of
+    /// following buffer_frames. Pending frames are bounded by the frame memory cap of the model.
+    pub write_in_background: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            steps: 400,
+            capture_every: 1,
+            warmup: 0,
+            output_dir: PathBuf::from("."),
+            buffer_frames: true,
+            write_in_background: false,
+        }
+    }
 }
 
+impl RunConfig {
+    /// Whether the frame after the given zero-based step is captured. The final frame always is.
+    fn captures(&self, step: usize) -> bool {
+        if step + 1 == self.steps {
+            return true;
+        }
+        step >= self.warmup && (step + 1 - self.warmup).is_multiple_of(self.capture_every)
+    }
+}
+
+/// A function of the iteration number that drives a population parameter.
+type Schedule = Box<dyn Fn(usize) -> f32 + Send + Sync>;
+
 /// Top-level simulation class.
 pub struct Model {
     // Physarum agents.
@@ -57,16 +530,105 @@ pub struct Model {
     // The grid they move on.
     grids: Vec<Grid>,
 
-    // Attraction table governs interaction across populations
-    attraction_table: Vec<Vec<f32>>,
+    // Attraction table governs interaction across populations. The diagonal holds the randomly
+    // sampled self-attraction factors, which scale what each population senses of its own trail.
+    attraction_table: AttractionMatrix,
+
+    // Whether the self-attraction factors are replaced by exactly 1.0 when combining grids.
+    normalize_self_attraction: bool,
+
+    // Global grid diffusivity, the standard deviation of the Gaussian blur applied every step.
+    diffusivity: f32,
+
+    // Initial placement of the agents.
+    placement: InitialPlacement,
 
-    // Global grid diffusivity.
-    diffusivity: usize,
+    // Number of blur passes per step.
+    diffuse_substeps: usize,
+
+    // Index given to the next agent created.
+    next_agent_index: usize,
 
     // Current model iteration.
-    iteration: i32,
+    iteration: usize,
 
     palette: Palette,
+
+    // Gamma and gain of every population.
+    tonemaps: Vec<TonemapConfig>,
+
+    // Whether run checks that the grids stay finite after every step.
+    check_finite: bool,
+
+    // Whether saved images carry an alpha channel derived from the trail intensity.
+    output_alpha: bool,
+
+    // Number of rows rendered at a time when writing PNG images, or None to render them whole.
+    strip_height: Option<usize>,
+
+    // Palettes that the render colors drift between over the run, if set.
+    palette_schedule: Option<(Palette, Palette)>,
+
+    // Iteration at which the current run ends, which drives the palette schedule.
+    total_iterations: Option<usize>,
+
+    // Seed of the random number generator used to initialize the model.
+    seed: u64,
+
+    // Thread pool for the parallel phases. The global rayon pool is used if not set.
+    #[cfg(feature = "native")]
+    thread_pool: Option<ThreadPool>,
+
+    // Number of the next captured frame. Frame numbers never repeat, and they only skip ahead to
+    // the end of a warmup.
+    frame_counter: usize,
+
+    // Number of leading iterations of the model during which no frames are captured.
+    warmup: usize,
+
+    // Captured frames waiting to be written.
+    img_data_vec: Vec<ImgData>,
+
+    // Maximum number of bytes of buffered frames during a run.
+    frame_memory_cap: usize,
+
+    // Format and quality of the written images.
+    frame_format: FrameFormat,
+
+    // Whether the white points are computed with a histogram-based approximate quantile.
+    approximate_quantile: bool,
+
+    // Scratch space for the white point quantiles, reused across grids and frames.
+    quantile_scratch: Mutex<Vec<f32>>,
+
+    // White points of the iteration they were computed at, shared by all renders of a frame.
+    white_point_cache: Mutex<Option<(usize, Vec<f32>)>>,
+
+    // Weight of the current frame when smoothing the white points across frames, if enabled.
+    white_point_smoothing: Option<f32>,
+
+    // Parameter schedules as (population id, parameter, schedule) triples.
+    schedules: Vec<(usize, Param, Schedule)>,
+
+    // Order of the phases within a step.
+    step_order: StepOrder,
+
+    // Whether agents spread their deposits over the four nearest cells.
+    smooth_deposit: bool,
+
+    // Trail statistics recorded at the captured steps of a run, if enabled.
+    time_series: Option<TimeSeries>,
+
+    // Rectangle (x0, y0, width, height) of the grid saved by save_to_image, the whole grid if
+    // unset.
+    viewport: Option<(usize, usize, usize, usize)>,
+
+    // Whether save_to_image centers the saved rectangle on the centroid of the trails.
+    recenter: bool,
+
+    // Cell size of the spatial index of agent positions and the index itself, rebuilt every step.
+    spatial_cell_size: Option<f32>,
+    spatial_index: Option<SpatialHash>,
 }
 
 impl Model {
@@ -74,152 +636,3388 @@ impl Model {
     const ATTRACTION_FACTOR_STD: f32 = 0.1;
     const REPULSION_FACTOR_MEAN: f32 = -1.0;
     const REPULSION_FACTOR_STD: f32 = 0.1;
+    const QUANTILE_BINS: usize = 4096;
+    const DEFAULT_FRAME_MEMORY_CAP: usize = 2 << 30;
 
     pub fn print_configurations(&self) {
+        let counts = self.population_counts();
         for (i, grid) in self.grids.iter().enumerate() {
-            println!("Grid {}: {}", i, grid.config);
+            println!("Grid {} ({} agents): {}", i, counts[i], grid.config);
         }
-        println!("Attraction table: {:#?}", self.attraction_table);
+        println!("Attraction table: {:#?}", self.attraction_table.rows());
+        println!(
+            "Diffusivity {} (blur radius {})",
+            self.diffusivity,
+            Blur::effective_radius(self.diffusivity)
+        );
     }
 
-    /// Construct a new model with random initial conditions and random configuration.
+    /// Construct a new model with random initial conditions and random configuration. Fails for
+    /// the same parameters as ModelBuilder::build, e.g. a diffusivity too small to blur.
     pub fn new(
         width: usize,
         height: usize,
         n_particles: usize,
         n_populations: usize,
-        diffusivity: usize,
-    ) -> Self {
+        diffusivity: f32,
+    ) -> Result<Self, Error> {
+        Self::new_with_placement(
+            width,
+            height,
+            n_particles,
+            n_populations,
+            diffusivity,
+            InitialPlacement::default(),
+        )
+    }
+
+    /// Construct a new model with a number of agents proportional to the grid area, so that the
+    /// patterns look alike across resolutions, e.g. in a 256x256 preview and a 1024x1024 render.
+    /// Typical densities are between 0.5 and 2.0 agents per cell.
+    pub fn new_with_density(
+        width: usize,
+        height: usize,
+        agents_per_cell: f64,
+        n_populations: usize,
+        diffusivity: f32,
+    ) -> Result<Self, Error> {
+        let n_particles = ((width * height) as f64 * agents_per_cell) as usize;
+        Self::new(width, height, n_particles, n_populations, diffusivity)
+    }
+
+    /// Construct a new model with random configuration and agents of every population initially
+    /// distributed according to the given placement.
+    pub fn new_with_placement(
+        width: usize,
+        height: usize,
+        n_particles: usize,
+        n_populations: usize,
+        diffusivity: f32,
+        placement: InitialPlacement,
+    ) -> Result<Self, Error> {
+        ModelBuilder::new()
+            .size(width, height)
+            .particles(n_particles)
+            .populations(n_populations)
+            .diffusivity(diffusivity)
+            .placement(placement)
+            .build()
+    }
+
+    /// Construct a new model with random initial conditions and the given population
+    /// configurations, one population per configuration. Every configuration is checked with
+    /// PopulationConfig::validate first.
+    pub fn new_with_configs(
+        width: usize,
+        height: usize,
+        n_particles: usize,
+        configs: Vec<PopulationConfig>,
+        diffusivity: f32,
+    ) -> Result<Self, Error> {
+        ModelBuilder::new()
+            .size(width, height)
+            .particles(n_particles)
+            .configs(configs)
+            .diffusivity(diffusivity)
+            .build()
+    }
+
+    /// Fail for a diffusivity that is not a valid standard deviation, or that rounds to a zero
+    /// blur radius, which would leave the trails undiffused.
+    fn check_diffusivity(diffusivity: f32) -> Result<(), Error> {
+        if !diffusivity.is_finite() || diffusivity < 0.0 {
+            return Err(Error::InvalidDiffusivity(diffusivity));
+        }
+        if Blur::effective_radius(diffusivity) == 0 {
+            return Err(Error::ZeroBlurRadius(diffusivity));
+        }
+        Ok(())
+    }
+
+    /// Distribution of the step distance multiplier of the agents of a population, drawn once per
+    /// agent.
+    fn speed_distribution(config: &PopulationConfig) -> Result<Normal<f32>, Error> {
+        Normal::new(1.0, config.speed_jitter).map_err(|_| {
+            Error::InvalidConfig(ConfigError {
+                param: Param::SpeedJitter,
+                value: config.speed_jitter,
+                expected: "non-negative",
+            })
+        })
+    }
+
+    /// Construct a model from a builder whose parameters have already been validated.
+    fn from_builder(builder: &ModelBuilder, seed: u64) -> Result<Self, Error> {
+        let ModelBuilder {
+            width,
+            height,
+            n_particles,
+            diffusivity,
+            placement,
+            shape,
+            ..
+        } = *builder;
+        let n_populations = builder.n_populations();
         let particles_per_grid = (n_particles as f64 / n_populations as f64).ceil() as usize;
         let n_particles = particles_per_grid * n_populations;
 
-        let mut rng = rand::thread_rng();
+        let mut rng = StdRng::seed_from_u64(seed);
 
         let attraction_distr =
             Normal::new(Self::ATTRACTION_FACTOR_MEAN, Self::ATTRACTION_FACTOR_STD).unwrap();
         let repulstion_distr =
             Normal::new(Self::REPULSION_FACTOR_MEAN, Self::REPULSION_FACTOR_STD).unwrap();
 
-        let mut attraction_table = Vec::with_capacity(n_populations);
+        let mut attraction_table = AttractionMatrix::new(n_populations);
         for i in 0..n_populations {
-            attraction_table.push(Vec::with_capacity(n_populations));
             for j in 0..n_populations {
-                attraction_table[i].push(if i == j {
-                    attraction_distr.sample(&mut rng)
-                } else {
-                    repulstion_distr.sample(&mut rng)
-                });
+                attraction_table.set(
+                    i,
+                    j,
+                    if i == j {
+                        attraction_distr.sample(&mut rng)
+                    } else {
+                        repulstion_distr.sample(&mut rng)
+                    },
+                );
             }
         }
 
-        Model {
+        let grids = (0..n_populations)
+            .map(|i| {
+                let mut grid = Grid::sampled(width, height, &builder.sampler, &mut rng);
+                match &builder.configs {
+                    Some(configs) => grid.config = configs[i].clone(),
+                    None => grid.config.validate()?,
+                }
+                grid.set_shape(shape);
+                Ok(grid)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let inscribed = InitialPlacement::Disk {
+            cx: 0.5 * width as f32,
+            cy: 0.5 * height as f32,
+            r: 0.5 * width.min(height) as f32,
+        };
+
+        let speed_distrs = grids
+            .iter()
+            .map(|grid| Model::speed_distribution(&grid.config))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Model {
             agents: (0..n_particles)
-                .map(|i| Agent::new(width, height, i / particles_per_grid, &mut rng))
-                .collect(),
-            grids: (0..n_populations)
-                .map(|_| Grid::new(width, height, &mut rng))
+                .map(|i| {
+                    let population_id = i / particles_per_grid;
+                    let k = i % particles_per_grid;
+                    let mut position =
+                        placement.sample(k, particles_per_grid, width, height, &mut rng);
+                    if !shape.contains(position.0, position.1, width, height) {
+                        position = inscribed.sample(k, particles_per_grid, width, height, &mut rng);
+                    }
+                    let (x, y, angle) = position;
+                    let speed = speed_distrs[population_id].sample(&mut rng).max(0.0);
+                    let mut agent = Agent::new(i, x, y, angle, speed, population_id);
+                    agent.energy = grids[population_id]
+                        .config
+                        .lifecycle
+                        .map_or(0.0, |lifecycle| lifecycle.initial_energy);
+                    agent
+                })
                 .collect(),
+            grids,
             attraction_table,
+            normalize_self_attraction: false,
             diffusivity,
+            placement,
+            diffuse_substeps: 1,
+            next_agent_index: n_particles,
             iteration: 0,
-            palette: random_palette(),
-        }
+            // Drawn last so that the palette doesn't shift the random draws of the simulation.
+            palette: builder
+                .palette
+                .unwrap_or_else(|| random_palette_seeded(&mut rng)),
+            tonemaps: vec![TonemapConfig::default(); n_populations],
+            check_finite: false,
+            output_alpha: false,
+            strip_height: None,
+            palette_schedule: None,
+            total_iterations: None,
+            seed,
+            #[cfg(feature = "native")]
+            thread_pool: None,
+            frame_counter: 0,
+            warmup: 0,
+            img_data_vec: Vec::new(),
+            frame_memory_cap: Self::DEFAULT_FRAME_MEMORY_CAP,
+            frame_format: FrameFormat::default(),
+            approximate_quantile: false,
+            quantile_scratch: Mutex::new(Vec::new()),
+            white_point_cache: Mutex::new(None),
+            white_point_smoothing: None,
+            schedules: Vec::new(),
+            step_order: StepOrder::default(),
+            smooth_deposit: false,
+            time_series: None,
+            viewport: None,
+            recenter: false,
+            spatial_cell_size: None,
+            spatial_index: None,
+        })
     }
 
-    fn pick_direction<R: Rng + ?Sized>(center: f32, left: f32, right: f32, rng: &mut R) -> f32 {
-        if (center > left) && (center > right) {
-            0.0
-        } else if (center < left) && (center < right) {
-            *[-1.0, 1.0].choose(rng).unwrap()
-        } else if left < right {
-            1.0
-        } else if right < left {
-            -1.0
-        } else {
-            0.0
+    /// Construct a copy of the model at a different resolution for quick previews, with the width
+    /// of the grids set to size and the aspect ratio kept. The copy starts from scratch with the
+    /// same seed, palette, attraction table and population configurations. Lengths in grid units
+    /// are scaled by the resolution ratio, see PopulationConfig::scaled, and so are the
+    /// diffusivity and the initial placement. The number of agents scales with the grid area, so
+    /// the agent density is preserved. Parameter schedules are not carried over.
+    pub fn preview_clone_at(&self, size: usize) -> Result<Model, Error> {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let ratio = size as f32 / width as f32;
+        let n_particles = (self.agents.len() as f32 * ratio * ratio).round() as usize;
+        let mut preview = ModelBuilder::new()
+            .size(size, height * size / width)
+            .particles(n_particles.max(self.grids.len()))
+            .populations(self.grids.len())
+            .diffusivity(self.diffusivity * ratio)
+            .placement(self.placement.scaled(ratio))
+            .seed(self.seed)
+            .palette(self.palette)
+            .build()?;
+
+        preview.attraction_table = self.attraction_table.clone();
+        for (grid, original) in preview.grids.iter_mut().zip(&self.grids) {
+            grid.rescale_settings_from(original, ratio);
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let speed_distrs = preview
+            .grids
+            .iter()
+            .map(|grid| Model::speed_distribution(&grid.config))
+            .collect::<Result<Vec<_>, _>>()?;
+        for agent in &mut preview.agents {
+            agent.speed = speed_distrs[agent.population_id].sample(&mut rng).max(0.0);
         }
+        preview.normalize_self_attraction = self.normalize_self_attraction;
+        preview.diffuse_substeps = self.diffuse_substeps;
+        preview.step_order = self.step_order;
+        preview.smooth_deposit = self.smooth_deposit;
+        preview.approximate_quantile = self.approximate_quantile;
+        preview.tonemaps = self.tonemaps.clone();
+        Ok(preview)
     }
 
-    /// Perform a single simulation step.
-    pub fn step(&mut self) {
-        // Combine grids
-        let grids = &mut self.grids;
-        combine(grids, &self.attraction_table);
+    /// Change the resolution of the running model, e.g. to run a coarse-to-fine schedule: settle
+    /// the large scale structure on a small grid, then refine it on a larger one. Both sizes must
+    /// be powers of two. The trail maps are resampled bilinearly, agent positions are mapped to
+    /// the new grid, and lengths in grid units are scaled by the geometric mean of the width and
+    /// height ratios, see PopulationConfig::scaled, and so are the diffusivity and the initial
+    /// placement. The number of agents is kept, so the agent density changes with the area. A
+    /// size at which the diffusivity rounds to a zero blur radius is an error.
+    pub fn rescale(&mut self, width: usize, height: usize) -> Result<(), Error> {
+        use crate::util::wrap;
 
+        if !width.is_power_of_two() || !height.is_power_of_two() {
+            return Err(Error::InvalidGridSize { width, height });
+        }
+        let (old_width, old_height) = (self.grids[0].width, self.grids[0].height);
+        let (rx, ry) = (
+            width as f32 / old_width as f32,
+            height as f32 / old_height as f32,
+        );
+        let ratio = (rx * ry).sqrt();
+        Model::check_diffusivity(self.diffusivity * ratio)?;
+
+        for grid in &mut self.grids {
+            grid.rescale(width, height, ratio);
+        }
         self.agents.par_iter_mut().for_each(|agent| {
-            let grid = &grids[agent.population_id];
-            let PopulationConfig {
-                sensor_distance,
-                sensor_angle,
-                rotation_angle,
-                step_distance,
-                ..
-            } = grid.config;
-            let (width, height) = (grid.width, grid.height);
+            agent.x = wrap(agent.x * rx, width as f32);
+            agent.y = wrap(agent.y * ry, height as f32);
+        });
+        self.diffusivity *= ratio;
+        self.placement = self.placement.scaled(ratio);
+        if let Some((x0, y0, w, h)) = self.viewport {
+            let scale = |v: usize, r: f32| ((v as f32 * r).round() as usize).max(1);
+            self.viewport = Some((
+                (x0 as f32 * rx) as usize,
+                (y0 as f32 * ry) as usize,
+                scale(w, rx),
+                scale(h, ry),
+            ));
+        }
+        if self.spatial_cell_size.is_some() {
+            self.rebuild_spatial_index();
+        }
+        self.invalidate_white_points();
+        Ok(())
+    }
 
-            let xc = agent.x + agent.angle.cos() * sensor_distance;
-            let yc = agent.y + agent.angle.sin() * sensor_distance;
-            let xl = agent.x + (agent.angle - sensor_angle).cos() * sensor_distance;
-            let yl = agent.y + (agent.angle - sensor_angle).sin() * sensor_distance;
-            let xr = agent.x + (agent.angle + sensor_angle).cos() * sensor_distance;
-            let yr = agent.y + (agent.angle + sensor_angle).sin() * sensor_distance;
+    /// Seed of the random number generator used to initialize the model.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
 
-            // Sense. We sense from the buffer because this is where we previously combined data
-            // from all the grid.
-            let trail_c = grid.get_buf(xc, yc);
-            let trail_l = grid.get_buf(xl, yl);
-            let trail_r = grid.get_buf(xr, yr);
+    /// Number of steps performed so far.
+    pub fn iteration(&self) -> usize {
+        self.iteration
+    }
 
-            // Rotate and move
-            let mut rng = rand::thread_rng();
-            let direction = Model::pick_direction(trail_c, trail_l, trail_r, &mut rng);
-            agent.rotate_and_move(direction, rotation_angle, step_distance, width, height);
-        });
+    /// Animate a population parameter over the run. The schedule maps the iteration number to the
+    /// parameter value (angles in radians) and is evaluated at the start of every step, before
+    /// sensing. Setting a new schedule for the same parameter replaces the old one. Parameters
+    /// without a schedule stay constant. A population id beyond the populations of the model is an
+    /// error.
+    pub fn set_param_schedule<F>(
+        &mut self,
+        population_id: usize,
+        param: Param,
+        schedule: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(usize) -> f32 + Send + Sync + 'static,
+    {
+        self.check_population(population_id)?;
+        self.schedules
+            .retain(|(id, p, _)| (*id, *p) != (population_id, param));
+        self.schedules
+            .push((population_id, param, Box::new(schedule)));
+        Ok(())
+    }
 
-        // Deposit
-        for agent in self.agents.iter() {
-            self.grids[agent.population_id].deposit(agent.x, agent.y);
+    /// Fail for a population id beyond the populations of the model.
+    fn check_population(&self, population_id: usize) -> Result<(), Error> {
+        if population_id >= self.grids.len() {
+            return Err(Error::InvalidPopulation {
+                population_id,
+                n_populations: self.grids.len(),
+            });
         }
+        Ok(())
+    }
 
-        // Diffuse + Decay
-        let diffusivity = self.diffusivity;
-        self.grids.par_iter_mut().for_each(|grid| {
-            grid.diffuse(diffusivity);
-        });
-        self.iteration += 1;
+    /// Configuration of a population.
+    pub fn population_config(&self, population_id: usize) -> &PopulationConfig {
+        &self.grids[population_id].config
     }
 
-    /// Output the current trail layer as a grayscale image.
-    pub fn save_to_image(&self, name: &str) {
-        let (width, height) = (self.grids[0].width, self.grids[0].height);
-        let mut img = image::RgbImage::new(width as u32, height as u32);
+    /// Mutable configuration of a population, for tuning a live model between steps. Changes take
+    /// effect at the next step, except for parameters with a schedule, which overwrites them. The
+    /// configuration is not validated, see PopulationConfig::validate.
+    pub fn population_config_mut(&mut self, population_id: usize) -> &mut PopulationConfig {
+        &mut self.grids[population_id].config
+    }
 
-        let max_values: Vec<_> = self
-            .grids
-            .iter()
-            .map(|grid| grid.quantile(0.999) * 1.5)
-            .collect();
+    /// Change the diffusivity of the model, which takes effect at the next step. A diffusivity
+    /// that rounds to a zero blur radius is an error and leaves the model unchanged.
+    pub fn set_diffusivity(&mut self, diffusivity: f32) -> Result<(), Error> {
+        Model::check_diffusivity(diffusivity)?;
+        self.diffusivity = diffusivity;
+        Ok(())
+    }
+
+    /// Use exactly 1.0 as the self-attraction factor of every population instead of the sampled
+    /// one. Without it, a single-population run senses its trail scaled by a random factor close to
+    /// 1.0, which slightly alters the behavior from run to run.
+    pub fn normalize_self_attraction(&mut self, enable: bool) {
+        self.normalize_self_attraction = enable;
+    }
+
+    /// The attraction table used to combine grids before sensing.
+    fn sensing_attraction_table(
+        table: &AttractionMatrix,
+        normalize_self: bool,
+    ) -> Cow<'_, AttractionMatrix> {
+        let mut table = Cow::Borrowed(table);
+        if normalize_self {
+            let table = table.to_mut();
+            for i in 0..table.len() {
+                table.set(i, i, 1.0);
+            }
+        }
+        table
+    }
+
+    /// How strongly each population is attracted to the trail of every population.
+    pub fn attraction_matrix(&self) -> &AttractionMatrix {
+        &self.attraction_table
+    }
+
+    /// Replace the attraction matrix, which must have a row for every population.
+    pub fn set_attraction_matrix(&mut self, matrix: AttractionMatrix) -> Result<(), Error> {
+        if matrix.len() != self.grids.len() {
+            return Err(Error::GridMismatch);
+        }
+        self.attraction_table = matrix;
+        Ok(())
+    }
+
+    /// Sensors of a population, fanned evenly across [-sensor_angle, sensor_angle] from left to
+    /// right. Each one is given by its offset as a fraction of the sensor angle, and by the cosine
+    /// and the sine of the angle it makes with the heading.
+    fn sensor_arms(config: &PopulationConfig) -> Vec<(f32, f32, f32)> {
+        let mid = config.n_sensors.saturating_sub(1) as f32 / 2.0;
+        (0..config.n_sensors)
+            .map(|k| {
+                let offset = if mid > 0.0 {
+                    (k as f32 - mid) / mid
+                } else {
+                    0.0
+                };
+                let (sin, cos) = (offset * config.sensor_angle).sin_cos();
+                (offset, cos, sin)
+            })
+            .collect()
+    }
 
-        for y in 0..height {
-            for x in 0..width {
-                let i = y * width + x;
-                let (mut r, mut g, mut b) = (0.0_f32, 0.0_f32, 0.0_f32);
-                for (grid, max_value, color) in
-                    multizip((&self.grids, &max_values, &self.palette.colors))
-                {
-                    let mut t = (grid.data()[i] / max_value).clamp(0.0, 1.0);
-                    t = t.powf(1.0 / 2.2); // gamma correction
-                    r += color.0[0] as f32 * t;
-                    g += color.0[1] as f32 * t;
-                    b += color.0[2] as f32 * t;
+    /// Sense the combined field, which mixes the trails of all the grids, and decide where to turn.
+    /// The sensors are given by sensor_arms, computed once per population, so that their
+    /// directions only take rotating the heading. Returns the turn as a fraction of the rotation
+    /// angle and the strongest sensed value.
+    fn sense<R: Rng + ?Sized>(
+        agent: &Agent,
+        grid: &Grid,
+        arms: &[(f32, f32, f32)],
+        rng: &mut R,
+    ) -> (f32, f32) {
+        let PopulationConfig {
+            sensor_distance,
+            rotation_angle,
+            steering,
+            n_sensors,
+            turn_randomness,
+            tie_break_bias,
+            ..
+        } = grid.config;
+        let (sin, cos) = agent.angle.sin_cos();
+        // Angle sum identities rotate the heading by the angle of the sensor.
+        let sensor = |&(_, arm_cos, arm_sin): &(f32, f32, f32)| {
+            let x = agent.x + (cos * arm_cos - sin * arm_sin) * sensor_distance;
+            let y = agent.y + (sin * arm_cos + cos * arm_sin) * sensor_distance;
+            (x, y)
+        };
+        let (xc, yc) = (
+            agent.x + cos * sensor_distance,
+            agent.y + sin * sensor_distance,
+        );
+
+        let (direction, trail) = match steering {
+            Steering::Sensors if n_sensors == 3 => {
+                let (xl, yl) = sensor(&arms[0]);
+                let (xr, yr) = sensor(&arms[2]);
+                let trail_c = grid.sense_at(xc, yc);
+                let trail_l = grid.sense_at(xl, yl);
+                let trail_r = grid.sense_at(xr, yr);
+                let direction =
+                    Model::pick_direction(trail_c, trail_l, trail_r, tie_break_bias, rng);
+                (direction, trail_c.max(trail_l).max(trail_r))
+            }
+            Steering::Sensors => {
+                // Sensor offsets run evenly from -1 on the left to 1 on the right. The agent turns
+                // towards the strongest sensor, breaking ties uniformly at random.
+                let (mut best, mut direction, mut ties) = (f32::NEG_INFINITY, 0.0, 0);
+                for arm in arms {
+                    let offset = arm.0;
+                    let (x, y) = sensor(arm);
+                    let trail = grid.sense_at(x, y);
+                    if trail > best {
+                        best = trail;
+                        direction = offset;
+                        ties = 1;
+                    } else if trail == best {
+                        ties += 1;
+                        if rng.gen_range(0..ties) == 0 {
+                            direction = offset;
+                        }
+                    }
                 }
-                r = r.clamp(0.0, 255.0);
-                g = g.clamp(0.0, 255.0);
-                b = b.clamp(0.0, 255.0);
-                img.put_pixel(x as u32, y as u32, image::Rgb([r as u8, g as u8, b as u8]));
+                (direction, if ties > 0 { best } else { 0.0 })
             }
+            Steering::Gradient => {
+                let (gx, gy) = grid.gradient(xc, yc);
+                let direction = Model::gradient_direction(agent.angle, gx, gy, rotation_angle);
+                (direction, grid.sense_at(xc, yc))
+            }
+        };
+        // Nothing is drawn without randomness, so that runs stay reproducible across versions.
+        if turn_randomness > 0.0 && rng.gen::<f32>() < turn_randomness {
+            (rng.gen_range(-1.0..=1.0), trail)
+        } else {
+            (direction, trail)
         }
+    }
 
-        img.save(name).unwrap();
+    /// Turn towards the strongest of three sensors. When the sensor ahead is the weakest, turn
+    /// right with probability bias and left otherwise.
+    fn pick_direction<R: Rng + ?Sized>(
+        center: f32,
+        left: f32,
+        right: f32,
+        bias: f32,
+        rng: &mut R,
+    ) -> f32 {
+        if (center > left) && (center > right) {
+            0.0
+        } else if (center < left) && (center < right) {
+            // An even tie-break is drawn as it always was, keeping seeded runs unchanged.
+            if bias == 0.5 {
+                *[-1.0, 1.0].choose(rng).unwrap()
+            } else if rng.gen::<f32>() < bias {
+                1.0
+            } else {
+                -1.0
+            }
+        } else if left < right {
+            1.0
+        } else if right < left {
+            -1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Fraction of the rotation angle, between -1 and 1, that turns a heading towards a gradient.
+    fn gradient_direction(angle: f32, gx: f32, gy: f32, rotation_angle: f32) -> f32 {
+        if (gx == 0.0 && gy == 0.0) || rotation_angle <= 0.0 {
+            return 0.0;
+        }
+        let turn = (gy.atan2(gx) - angle + PI).rem_euclid(TAU) - PI;
+        (turn / rotation_angle).clamp(-1.0, 1.0)
+    }
+
+    /// Split the diffusion of every step into a number of blur passes, one by default. The decay is
+    /// still applied once per step, while the trail spreads as if blurred with the diffusivity
+    /// scaled by the square root of the number of substeps. This lets the field keep up with agents
+    /// that sense far away without increasing the step count. Zero substeps are an error, since
+    /// the trails would never spread.
+    pub fn set_diffuse_substeps(&mut self, substeps: usize) -> Result<(), Error> {
+        if substeps == 0 {
+            return Err(Error::ZeroCount("diffusion substeps"));
+        }
+        self.diffuse_substeps = substeps;
+        Ok(())
+    }
+
+    /// Change the order of the phases within a step, e.g. to diffuse the trails before the agents
+    /// move or deposit after diffusion.
+    pub fn set_step_order(&mut self, step_order: StepOrder) {
+        self.step_order = step_order;
+    }
+
+    /// Spread every deposit bilinearly over the four cells nearest to the agent instead of dropping
+    /// it into a single cell. This avoids the blocky nucleation of trails around cell boundaries.
+    pub fn set_smooth_deposit(&mut self, enable: bool) {
+        self.smooth_deposit = enable;
+    }
+
+    /// Run the parallel phases of the simulation, as well as rendering and image encoding, on a
+    /// dedicated pool of n threads instead of the global rayon pool. Zero lets rayon pick the
+    /// number of threads.
+    #[cfg(feature = "native")]
+    pub fn set_thread_pool(&mut self, n: usize) -> Result<(), Error> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build()
+            .map_err(|err| Error::ThreadPool(err.to_string()))?;
+        self.thread_pool = Some(pool);
+        Ok(())
+    }
+
+    /// Run f on the thread pool of the model, or on the global rayon pool if none is set. Rendering
+    /// and encoding go through here, so that they respect the same limit as the steps.
+    #[cfg(feature = "native")]
+    fn with_pool<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        match &self.thread_pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
+    /// Without threads, everything runs on the calling thread.
+    #[cfg(not(feature = "native"))]
+    fn with_pool<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        f()
+    }
+
+    /// Perform a single simulation step.
+    #[cfg(feature = "native")]
+    pub fn step(&mut self) {
+        match self.thread_pool.take() {
+            Some(pool) => {
+                pool.install(|| self.step_impl());
+                self.thread_pool = Some(pool);
+            }
+            None => self.step_impl(),
+        }
+    }
+
+    /// Perform a single simulation step.
+    #[cfg(not(feature = "native"))]
+    pub fn step(&mut self) {
+        self.step_impl();
+    }
+
+    fn step_impl(&mut self) {
+        // Update scheduled parameters
+        for (population_id, param, schedule) in &self.schedules {
+            self.grids[*population_id]
+                .config
+                .set(*param, schedule(self.iteration));
+        }
+
+        for phase in self.step_order.0 {
+            match phase {
+                Phase::Combine => self.combine_grids(),
+                Phase::Move => self.move_agents(),
+                Phase::Deposit => self.deposit_trails(),
+                Phase::Diffuse => self.diffuse_trails(),
+            }
+        }
+        if self.spatial_cell_size.is_some() {
+            self.rebuild_spatial_index();
+        }
+        self.iteration += 1;
+    }
+
+    /// Mix the trail maps into the sensing fields.
+    fn combine_grids(&mut self) {
+        let attraction_table =
+            Model::sensing_attraction_table(&self.attraction_table, self.normalize_self_attraction);
+        combine(&mut self.grids, attraction_table.rows());
+    }
+
+    /// Sense, turn and move the agents, then update the populations with a lifecycle.
+    fn move_agents(&mut self) {
+        let grids = &self.grids;
+        let (seed, iteration) = (self.seed, self.iteration);
+        let arms: Vec<_> = grids
+            .iter()
+            .map(|grid| Model::sensor_arms(&grid.config))
+            .collect();
+        self.agents.par_iter_mut().for_each(|agent| {
+            let grid = &grids[agent.population_id];
+            let PopulationConfig {
+                rotation_angle,
+                step_distance,
+                inertia,
+                step_jitter,
+                lifecycle,
+                ..
+            } = grid.config;
+            let (width, height) = (grid.width, grid.height);
+            let mut rng = agent_rng(seed, agent.i, iteration);
+
+            let arms = &arms[agent.population_id];
+            let (direction, trail) = Model::sense(agent, grid, arms, &mut rng);
+            agent.sensed = trail;
+            let from = (agent.x, agent.y);
+
+            if let Some(lifecycle) = lifecycle {
+                agent.energy += lifecycle.energy_gain * trail - lifecycle.energy_loss;
+            }
+
+            // Rotate and move
+            let step_distance = if step_jitter > 0.0 {
+                let z: f32 = rng.sample(StandardNormal);
+                step_distance * (1.0 + step_jitter * z).max(0.0)
+            } else {
+                step_distance
+            };
+            agent.rotate_and_move(
+                direction,
+                rotation_angle,
+                inertia,
+                step_distance,
+                width,
+                height,
+            );
+            if !grid.shape().contains(agent.x, agent.y, width, height) {
+                agent.bounce(from, 0.5 * width as f32, 0.5 * height as f32);
+            }
+        });
+
+        // Reproduce and die
+        if self
+            .grids
+            .iter()
+            .any(|grid| grid.config.lifecycle.is_some())
+        {
+            self.update_lifecycles();
+        }
+    }
+
+    /// Deposit the trail of every agent onto the grid of its population, except for the agents
+    /// that sensed less than the deposit threshold in their last move.
+    fn deposit_trails(&mut self) {
+        // Deposits are keyed by the unique agent index, which makes the accumulated trails
+        // independent of the order of the agents.
+        let mut deposits = vec![Vec::new(); self.grids.len()];
+        for agent in self.agents.iter() {
+            let config = &self.grids[agent.population_id].config;
+            if config.deposit_threshold > 0.0 && agent.sensed < config.deposit_threshold {
+                continue;
+            }
+            let (x, y) = agent.deposit_position(config.deposit_offset);
+            deposits[agent.population_id].push((x, y, agent.i));
+        }
+        let smooth = self.smooth_deposit;
+        let taps: Vec<_> = self
+            .grids
+            .iter()
+            .map(|grid| grid.config.deposit_kernel.taps())
+            .collect();
+        self.grids
+            .par_iter_mut()
+            .zip(deposits)
+            .zip(taps)
+            .for_each(|((grid, deposits), taps)| grid.deposit_batch(&deposits, &taps, smooth));
+    }
+
+    /// Diffuse and decay the trail maps, keeping them within their caps.
+    fn diffuse_trails(&mut self) {
+        let (diffusivity, substeps) = (self.diffusivity, self.diffuse_substeps);
+        self.grids.par_iter_mut().for_each(|grid| {
+            grid.apply_cap();
+            grid.diffuse_and_decay(diffusivity, substeps);
+            grid.apply_cap();
+        });
+    }
+
+    /// Split agents whose energy exceeds the threshold and remove the ones that ran out of it.
+    fn update_lifecycles(&mut self) {
+        let grids = &self.grids;
+        let offspring: Vec<_> = self
+            .agents
+            .par_iter_mut()
+            .filter_map(|agent| {
+                let lifecycle = grids[agent.population_id].config.lifecycle?;
+                if agent.energy <= lifecycle.split_threshold {
+                    return None;
+                }
+                // The offspring shares the energy and heads in the opposite direction.
+                agent.energy *= 0.5;
+                let mut child = agent.clone();
+                child.angle = wrap_angle(child.angle + PI);
+                Some(child)
+            })
+            .collect();
+
+        self.agents.retain(|agent| {
+            grids[agent.population_id].config.lifecycle.is_none() || agent.energy > 0.0
+        });
+        for mut child in offspring {
+            child.i = self.next_agent_index;
+            self.next_agent_index += 1;
+            self.agents.push(child);
+        }
+    }
+
+    /// Maintain a spatial index of the agent positions with cells of the given size, rebuilt after
+    /// every step, or drop it with None. The index speeds up local_density considerably at the
+    /// cost of rebuilding it every step. Cells about as large as the typical query radius work
+    /// best.
+    pub fn set_spatial_index(&mut self, cell_size: Option<f32>) {
+        self.spatial_cell_size = cell_size;
+        self.spatial_index = None;
+        if cell_size.is_some() {
+            self.rebuild_spatial_index();
+        }
+    }
+
+    fn rebuild_spatial_index(&mut self) {
+        let cell_size = self.spatial_cell_size.unwrap_or(f32::INFINITY);
+        let positions: Vec<_> = self.agents.iter().map(|agent| (agent.x, agent.y)).collect();
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        self.spatial_index = Some(SpatialHash::new(&positions, width, height, cell_size));
+    }
+
+    /// Number of agents of all populations within a radius of a position, measuring distances
+    /// across the periodic grid boundaries. Without a spatial index every agent is checked.
+    pub fn local_density(&self, x: f32, y: f32, radius: f32) -> usize {
+        match &self.spatial_index {
+            Some(index) => index.count_within(x, y, radius),
+            None => {
+                let positions: Vec<_> =
+                    self.agents.iter().map(|agent| (agent.x, agent.y)).collect();
+                let (width, height) = (self.grids[0].width, self.grids[0].height);
+                SpatialHash::new(&positions, width, height, f32::INFINITY)
+                    .count_within(x, y, radius)
+            }
+        }
+    }
+
+    /// Mean heading of the agents of a population in every grid cell, as row-major (cos, sin)
+    /// averages. The length of a mean heading measures how aligned the agents of the cell are,
+    /// from 1 when they all point the same way to 0 for opposing headings or an empty cell.
+    pub fn flow_field(&self, population_id: usize) -> Vec<(f32, f32)> {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let mut sums = vec![(0.0, 0.0, 0_usize); width * height];
+        for agent in &self.agents {
+            if agent.population_id == population_id {
+                let i = (agent.x as usize).min(width - 1);
+                let j = (agent.y as usize).min(height - 1);
+                let (cos, sin, count) = &mut sums[j * width + i];
+                *cos += agent.angle.cos();
+                *sin += agent.angle.sin();
+                *count += 1;
+            }
+        }
+        sums.into_iter()
+            .map(|(cos, sin, count)| match count {
+                0 => (0.0, 0.0),
+                _ => (cos / count as f32, sin / count as f32),
+            })
+            .collect()
+    }
+
+    /// Render the flow field of a population as a direction map: the hue of a cell is the angle of
+    /// its mean heading, and the brightness is the length of it, so empty cells are black.
+    pub fn render_flow_field(&self, population_id: usize) -> RgbImage {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let field = self.flow_field(population_id);
+        RgbImage::from_fn(width as u32, height as u32, |x, y| {
+            let (cos, sin) = field[y as usize * width + x as usize];
+            render::hsv(sin.atan2(cos), 1.0, cos.hypot(sin))
+        })
+    }
+
+    /// Write the flow field of a population to an image file, see render_flow_field.
+    #[cfg(feature = "native")]
+    pub fn export_flow_field<P: AsRef<Path>>(
+        &self,
+        population_id: usize,
+        path: P,
+    ) -> Result<(), Error> {
+        self.render_flow_field(population_id).save(path)?;
+        Ok(())
+    }
+
+    /// Add agents to a population, placed according to the given distribution, e.g. to start a new
+    /// emitter partway through a run. New agents get fresh indices, a speed drawn like at
+    /// construction and the initial energy of the population lifecycle, if any. The placement is
+    /// reproducible given the model seed and iteration. Fails if the population does not exist
+    /// or its speed jitter was set to an invalid value.
+    pub fn spawn_agents(
+        &mut self,
+        count: usize,
+        population_id: usize,
+        placement: InitialPlacement,
+    ) -> Result<(), Error> {
+        self.check_population(population_id)?;
+        let grid = &self.grids[population_id];
+        let (width, height) = (grid.width, grid.height);
+        let speed_distr = Model::speed_distribution(&grid.config)?;
+        let energy = grid
+            .config
+            .lifecycle
+            .map_or(0.0, |lifecycle| lifecycle.initial_energy);
+        let mut rng = agent_rng(self.seed, self.next_agent_index, self.iteration);
+        for k in 0..count {
+            let (x, y, angle) = placement.sample(k, count, width, height, &mut rng);
+            let speed = speed_distr.sample(&mut rng).max(0.0);
+            let mut agent = Agent::new(self.next_agent_index, x, y, angle, speed, population_id);
+            agent.energy = energy;
+            self.agents.push(agent);
+            self.next_agent_index += 1;
+        }
+        if self.spatial_cell_size.is_some() {
+            self.rebuild_spatial_index();
+        }
+        Ok(())
+    }
+
+    /// Remove the agents for which the predicate returns true.
+    pub fn kill_agents<F>(&mut self, predicate: F)
+    where
+        F: Fn(&AgentView) -> bool,
+    {
+        let grids = &self.grids;
+        self.agents.retain(|agent| {
+            !predicate(&AgentView {
+                x: agent.x,
+                y: agent.y,
+                angle: agent.angle,
+                energy: agent.energy,
+                population_id: agent.population_id,
+                grids,
+            })
+        });
+        if self.spatial_cell_size.is_some() {
+            self.rebuild_spatial_index();
+        }
+    }
+
+    /// Move agents between populations. The callback is called for every agent and returns the id
+    /// of its new population, or None to keep the current one. Moved agents sense, follow and
+    /// deposit on the grid of their new population starting with the next step. Reassignment
+    /// breaks the even split of agents across populations, see population_counts. If the callback
+    /// returns a population id beyond the populations of the model, no agent is moved.
+    pub fn reassign_populations<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: Fn(&AgentView) -> Option<usize> + Sync,
+    {
+        let grids = &self.grids;
+        let reassigned: Vec<_> = self
+            .agents
+            .par_iter()
+            .map(|agent| {
+                f(&AgentView {
+                    x: agent.x,
+                    y: agent.y,
+                    angle: agent.angle,
+                    energy: agent.energy,
+                    population_id: agent.population_id,
+                    grids,
+                })
+            })
+            .collect();
+        if let Some(&population_id) = reassigned.iter().flatten().find(|&&id| id >= grids.len()) {
+            return Err(Error::InvalidPopulation {
+                population_id,
+                n_populations: grids.len(),
+            });
+        }
+        self.agents
+            .par_iter_mut()
+            .zip(reassigned)
+            .for_each(|(agent, population_id)| {
+                if let Some(population_id) = population_id {
+                    agent.population_id = population_id;
+                }
+            });
+        Ok(())
+    }
+
+    /// Number of agents in every population.
+    pub fn population_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.grids.len()];
+        for agent in &self.agents {
+            counts[agent.population_id] += 1;
+        }
+        counts
+    }
+
+    /// Give a population an extra chemical layer, e.g. a repellent that keeps its agents from
+    /// crowding, and return its channel index.
+    pub fn add_channel(&mut self, population_id: usize, config: ChannelConfig) -> usize {
+        self.grids[population_id].add_channel(config)
+    }
+
+    /// Check that the trails of all populations are finite, and report the first one that isn't.
+    pub fn check_finite(&self) -> Result<(), Error> {
+        match self.grids.iter().position(Grid::has_non_finite) {
+            Some(population_id) => Err(Error::NonFinite {
+                population_id,
+                iteration: self.iteration,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Make run check that the trails stay finite after every step, and stop with an error as
+    /// soon as one doesn't. This costs a pass over every grid per step.
+    pub fn set_check_finite(&mut self, enable: bool) {
+        self.check_finite = enable;
+    }
+
+    /// Copy of the grids of all populations, to be restored later with restore_grids.
+    pub fn snapshot_grids(&self) -> Vec<Grid> {
+        self.grids.clone()
+    }
+
+    /// Replace the grids of all populations, e.g. with a snapshot taken earlier, to try different
+    /// settings from the same state. The agents are left as they are. The grids must have the
+    /// same count and dimensions as the current ones.
+    pub fn restore_grids(&mut self, grids: Vec<Grid>) -> Result<(), Error> {
+        let compatible = grids.len() == self.grids.len()
+            && grids
+                .iter()
+                .zip(&self.grids)
+                .all(|(new, old)| (new.width, new.height) == (old.width, old.height));
+        if !compatible {
+            return Err(Error::GridMismatch);
+        }
+        self.grids = grids;
+        self.invalidate_white_points();
+        Ok(())
+    }
+
+    /// Bound the trail values of a population, or lift the bound with None.
+    pub fn set_max_value_cap(&mut self, population_id: usize, cap: Option<f32>) {
+        self.grids[population_id].max_value_cap = cap;
+        self.invalidate_white_points();
+    }
+
+    /// Bound the trail values of every population, or lift the bounds with None. This keeps the
+    /// grids finite when a configuration makes the trails grow without bound, see
+    /// set_max_value_cap.
+    pub fn set_clamp_values(&mut self, max: Option<f32>) {
+        for grid in &mut self.grids {
+            grid.max_value_cap = max;
+        }
+        self.invalidate_white_points();
+    }
+
+    /// Set the energy dynamics of a population. All of its agents are reset to the initial energy.
+    pub fn set_lifecycle(&mut self, population_id: usize, lifecycle: Option<Lifecycle>) {
+        self.grids[population_id].config.lifecycle = lifecycle;
+        let energy = lifecycle.map_or(0.0, |lifecycle| lifecycle.initial_energy);
+        for agent in &mut self.agents {
+            if agent.population_id == population_id {
+                agent.energy = energy;
+            }
+        }
+    }
+
+    /// Seed the trail map of a population from a grayscale version of an image file. The image is
+    /// resampled to the grid dimensions if necessary.
+    #[cfg(feature = "native")]
+    pub fn seed_grid_from_image<P: AsRef<Path>>(
+        &mut self,
+        population_id: usize,
+        path: P,
+    ) -> image::ImageResult<()> {
+        let img = image::open(path)?.into_luma8();
+        self.grids[population_id].seed_from_image(&img);
+        self.invalidate_white_points();
+        Ok(())
+    }
+
+    /// Advance the simulation by a number of steps without capturing or rendering anything, so that
+    /// the reported timings reflect pure compute.
+    pub fn run_headless(&mut self, steps: usize) -> RunStats {
+        let mut stats = RunStats::default();
+        let start = Instant::now();
+        for _ in 0..steps {
+            stats.record_step(self);
+        }
+        stats.total_ms = start.elapsed().as_secs_f64() * 1e3;
+        stats
+    }
+
+    /// Advance the simulation and write the captured frames as `out_<frame>.<ext>` images to the
+    /// output directory, in the image format of the model. Frames are rendered as soon as they are
+    /// captured and buffered, then encoded in parallel at the end of the run, unless the run
+    /// configuration asks to write them in the background. Step timings exclude rendering, the
+    /// total time includes it.
+    #[cfg(feature = "native")]
+    pub fn run(&mut self, config: &RunConfig) -> Result<RunStats, Error> {
+        std::fs::create_dir_all(&config.output_dir)?;
+        self.total_iterations = Some(self.iteration + config.steps);
+        let mut stats = RunStats {
+            warmup_steps: config.warmup.min(config.steps),
+            ..RunStats::default()
+        };
+        let mut writer = config.write_in_background.then(|| {
+            let (width, height) = (self.grids[0].width, self.grids[0].height);
+            let frame_size = width * height * PixelFormat::Rgb8.bytes_per_pixel();
+            let capacity = (self.frame_memory_cap / frame_size).max(1);
+            FrameWriter::new(config.output_dir.clone(), self.frame_format, capacity)
+        });
+        self.frame_counter = self.frame_counter.max(config.warmup);
+        let start = Instant::now();
+        for step in 0..config.steps {
+            stats.record_step(self);
+            if self.check_finite {
+                self.check_finite()?;
+            }
+            if !config.captures(step) || self.in_warmup() {
+                continue;
+            }
+            if let Some(mut time_series) = self.time_series.take() {
+                time_series.record(self);
+                self.time_series = Some(time_series);
+            }
+            if let Some(writer) = &mut writer {
+                let frame = self.render_image_data();
+                writer.send(frame)?;
+            } else if config.buffer_frames {
+                self.capture_frame(&config.output_dir)?;
+            } else {
+                self.save_frame(&config.output_dir)?;
+            }
+        }
+        if let Some(writer) = writer {
+            writer.finish()?;
+        }
+        self.flush_image_data(&config.output_dir)?;
+        stats.total_ms = start.elapsed().as_secs_f64() * 1e3;
+        Ok(stats)
+    }
+
+    /// Record the trail mass and entropy of every population whenever a run captures a frame.
+    /// Enabling the recording clears the samples recorded so far.
+    pub fn set_record_time_series(&mut self, enable: bool) {
+        self.time_series = enable.then(TimeSeries::default);
+    }
+
+    /// Trail statistics recorded so far, empty unless recording is enabled.
+    pub fn time_series(&self) -> TimeSeries {
+        self.time_series.clone().unwrap_or_default()
+    }
+
+    /// Render the current frame and write it to a directory as `out_<frame>.<ext>`, bypassing the
+    /// buffer of captured frames. The frame is opaque RGB regardless of set_output_alpha.
+    #[cfg(feature = "native")]
+    pub fn save_frame<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), Error> {
+        let frame = self.next_frame();
+        let name = self.frame_format.file_name(frame);
+        let path = dir.as_ref().join(name);
+        self.with_pool(|| {
+            let img = self.render();
+            let (width, height) = img.dimensions();
+            self.frame_format
+                .save_buffer(path, &img, width, height, ColorType::Rgb8)
+        })?;
+        Ok(())
+    }
+
+    /// Write images in the given format, PNG by default. Formats the image crate can only read
+    /// are rejected.
+    pub fn set_image_format(&mut self, format: ImageFormat) -> Result<(), Error> {
+        self.set_frame_format(FrameFormat {
+            format,
+            ..self.frame_format
+        })
+    }
+
+    /// Quality of lossy image formats from 1 to 100, 90 by default.
+    pub fn set_image_quality(&mut self, quality: u8) -> Result<(), Error> {
+        self.set_frame_format(FrameFormat {
+            quality,
+            ..self.frame_format
+        })
+    }
+
+    /// Set the format and quality of the written images at once, e.g. FrameFormat::jpeg(80).
+    pub fn set_frame_format(&mut self, format: FrameFormat) -> Result<(), Error> {
+        format.validate()?;
+        self.frame_format = format;
+        Ok(())
+    }
+
+    /// Format and quality of the written images.
+    pub fn frame_format(&self) -> FrameFormat {
+        self.frame_format
+    }
+
+    /// Number the next captured frame, starting no earlier than the warmup.
+    fn next_frame(&mut self) -> usize {
+        self.frame_counter = self.frame_counter.max(self.warmup) + 1;
+        self.frame_counter - 1
+    }
+
+    /// Run the first given number of iterations of the model without capturing frames, e.g. to
+    /// skip the settling from random noise. The simulation advances normally, but save_image_data
+    /// and the captures of run and frames do nothing until the model reaches the warmup
+    /// iteration. Captured frames are numbered from the warmup on, so the first frame after a
+    /// warmup of 300 is `out_00000300`. Unlike RunConfig::warmup, this counts iterations of the
+    /// model rather than steps of a single run.
+    pub fn set_warmup(&mut self, iterations: usize) {
+        self.warmup = iterations;
+    }
+
+    /// Whether the model is still within its warmup.
+    fn in_warmup(&self) -> bool {
+        self.iteration < self.warmup
+    }
+
+    /// Buffer the current frame, writing out all buffered frames once they take more memory than
+    /// the frame memory cap.
+    #[cfg(feature = "native")]
+    fn capture_frame<P: AsRef<Path> + Sync>(&mut self, dir: P) -> Result<(), Error> {
+        self.save_image_data();
+        let buffered: usize = self.img_data_vec.iter().map(ImgData::size_in_bytes).sum();
+        if buffered > self.frame_memory_cap {
+            self.flush_image_data(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Limit the memory taken by buffered frames during a run, 2 GiB by default. Once the limit is
+    /// exceeded, the buffered frames are written out and the buffer is cleared.
+    pub fn set_frame_memory_cap(&mut self, bytes: usize) {
+        self.frame_memory_cap = bytes;
+    }
+
+    /// Render the current frame and add it to the buffer of captured frames, unless the model is
+    /// still within its warmup.
+    pub fn save_image_data(&mut self) {
+        if self.in_warmup() {
+            return;
+        }
+        let frame = self.render_image_data();
+        self.img_data_vec.push(frame);
+    }
+
+    /// Render the current frame as the next captured frame.
+    fn render_image_data(&mut self) -> ImgData {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let mut pixels = vec![0; width * height * PixelFormat::Rgb8.bytes_per_pixel()];
+        self.render_into(&mut pixels, PixelFormat::Rgb8).unwrap();
+        let frame = self.next_frame();
+        ImgData {
+            width: width as u32,
+            height: height as u32,
+            iteration: self.iteration,
+            frame,
+            pixels,
+        }
+    }
+
+    /// Remove and return the buffered frames.
+    pub fn take_image_data(&mut self) -> Vec<ImgData> {
+        std::mem::take(&mut self.img_data_vec)
+    }
+
+    /// Write all buffered frames to a directory and clear the buffer.
+    #[cfg(feature = "native")]
+    pub fn flush_image_data<P: AsRef<Path> + Sync>(&mut self, dir: P) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        self.with_pool(|| imgdata::save_all_as(&self.img_data_vec, dir, &self.frame_format))?;
+        self.img_data_vec.clear();
+        Ok(())
+    }
+
+    /// White points used to normalize every grid when rendering. They are computed once per
+    /// iteration and reused by every subsequent render of the same frame. With smoothing enabled,
+    /// they are blended with the white points of the previously rendered frame.
+    fn white_points(&self) -> Vec<f32> {
+        let mut cache = self.white_point_cache.lock().unwrap();
+        match &*cache {
+            Some((iteration, white_points)) if *iteration == self.iteration => white_points.clone(),
+            previous => {
+                let mut white_points = self.compute_white_points();
+                if let (Some(alpha), Some((_, previous))) = (self.white_point_smoothing, previous) {
+                    for (white_point, previous) in white_points.iter_mut().zip(previous) {
+                        *white_point = previous + alpha * (*white_point - previous);
+                    }
+                }
+                *cache = Some((self.iteration, white_points.clone()));
+                white_points
+            }
+        }
+    }
+
+    fn compute_white_points(&self) -> Vec<f32> {
+        let mut scratch = self.quantile_scratch.lock().unwrap();
+        self.grids
+            .iter()
+            .map(|grid| {
+                let quantile = if self.approximate_quantile {
+                    grid.quantile_approx(WHITE_POINT_QUANTILE, Self::QUANTILE_BINS)
+                } else {
+                    grid.quantile_with_scratch(WHITE_POINT_QUANTILE, &mut scratch)
+                };
+                quantile * WHITE_POINT_SCALE
+            })
+            .collect()
+    }
+
+    /// Compute the white points of rendered frames from a histogram instead of an exact quantile,
+    /// which is considerably faster for large grids.
+    pub fn set_approximate_quantile(&mut self, enable: bool) {
+        self.approximate_quantile = enable;
+        self.invalidate_white_points();
+    }
+
+    /// Smooth the white points exponentially across rendered frames to avoid brightness flicker in
+    /// videos, or render every frame independently with None. Alpha in (0, 1] is the weight of the
+    /// current frame: 1.0 disables smoothing and smaller values react more slowly to changes. The
+    /// first frame, and the first one after the grids change outside of a step, is not smoothed.
+    pub fn set_white_point_smoothing(&mut self, alpha: Option<f32>) {
+        self.white_point_smoothing = alpha.map(|alpha| alpha.clamp(f32::EPSILON, 1.0));
+    }
+
+    /// Drop the cached white points after the grids changed outside of a step.
+    fn invalidate_white_points(&mut self) {
+        *self.white_point_cache.get_mut().unwrap() = None;
+    }
+
+    /// Render with the given palette from now on, dropping the palette schedule if one is set.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.palette_schedule = None;
+    }
+
+    /// Let the render colors drift linearly from one palette at iteration zero to another at the
+    /// end of the run, as given by run, frames or set_total_iterations. Until the end is known,
+    /// the start palette is used.
+    pub fn set_palette_schedule(&mut self, start: Palette, end: Palette) {
+        self.palette_schedule = Some((start, end));
+    }
+
+    /// Set the iteration at which the run ends, for the palette schedule. This is only needed when
+    /// stepping the model by hand, run and frames set it themselves.
+    pub fn set_total_iterations(&mut self, total_iterations: usize) {
+        self.total_iterations = Some(total_iterations);
+    }
+
+    /// Palette of the current iteration.
+    fn current_palette(&self) -> Palette {
+        match (self.palette_schedule, self.total_iterations) {
+            (Some((start, end)), Some(total)) if total > 0 => {
+                start.lerp(&end, self.iteration as f32 / total as f32)
+            }
+            (Some((start, _)), _) => start,
+            (None, _) => self.palette,
+        }
+    }
+
+    /// Tonemap every population with the same gamma and gain.
+    pub fn set_tonemap(&mut self, config: TonemapConfig) {
+        self.tonemaps.fill(config);
+    }
+
+    /// Tonemap a population with its own gamma and gain, e.g. to brighten a faint background
+    /// population without blowing out the others. Populations without their own tonemapping use
+    /// the default one, or the one given to set_tonemap.
+    pub fn set_population_tonemap(&mut self, population_id: usize, config: TonemapConfig) {
+        self.tonemaps[population_id] = config;
+    }
+
+    /// Tonemap the i-th cell of all grids and blend them with the palette colors.
+    fn tonemap(&self, i: usize, max_values: &[f32], palette: &Palette) -> [u8; 3] {
+        let (mut r, mut g, mut b) = (0.0_f32, 0.0_f32, 0.0_f32);
+        let layers = multizip((&self.grids, &self.tonemaps, max_values, &palette.colors));
+        for (grid, tonemap, max_value, color) in layers {
+            let t = tonemap.intensity(grid.data()[i], *max_value);
+            r += color.0[0] as f32 * t;
+            g += color.0[1] as f32 * t;
+            b += color.0[2] as f32 * t;
+        }
+        r = r.clamp(0.0, 255.0);
+        g = g.clamp(0.0, 255.0);
+        b = b.clamp(0.0, 255.0);
+        [r as u8, g as u8, b as u8]
+    }
+
+    /// Like tonemap, but with the summed intensity of all populations as alpha. The color is not
+    /// premultiplied: compositing the pixel over black gives back the opaque tonemapped color.
+    fn tonemap_rgba(&self, i: usize, max_values: &[f32], palette: &Palette) -> [u8; 4] {
+        let (mut r, mut g, mut b, mut total) = (0.0_f32, 0.0_f32, 0.0_f32, 0.0_f32);
+        let layers = multizip((&self.grids, &self.tonemaps, max_values, &palette.colors));
+        for (grid, tonemap, max_value, color) in layers {
+            let t = tonemap.intensity(grid.data()[i], *max_value);
+            r += color.0[0] as f32 * t;
+            g += color.0[1] as f32 * t;
+            b += color.0[2] as f32 * t;
+            total += t;
+        }
+        let alpha = total.min(1.0);
+        if alpha <= 0.0 {
+            return [0, 0, 0, 0];
+        }
+        r = (r / alpha).clamp(0.0, 255.0);
+        g = (g / alpha).clamp(0.0, 255.0);
+        b = (b / alpha).clamp(0.0, 255.0);
+        [r as u8, g as u8, b as u8, (alpha * 255.0).round() as u8]
+    }
+
+    /// Render the current frame into a caller-provided buffer of row-major pixels, which must hold
+    /// exactly width * height pixels of the given format.
+    pub fn render_into(&self, buf: &mut [u8], format: PixelFormat) -> Result<(), Error> {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let expected = width * height * format.bytes_per_pixel();
+        if buf.len() != expected {
+            return Err(Error::BufferSize {
+                expected,
+                actual: buf.len(),
+            });
+        }
+
+        self.with_pool(|| {
+            let max_values = self.white_points();
+            let palette = self.current_palette();
+            for (i, pixel) in buf.chunks_exact_mut(format.bytes_per_pixel()).enumerate() {
+                format.write(pixel, self.tonemap(i, &max_values, &palette));
+            }
+        });
+        Ok(())
+    }
+
+    /// Render the current trail layers blended with the palette colors.
+    pub fn render(&self) -> RgbImage {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let mut buf = vec![0; width * height * PixelFormat::Rgb8.bytes_per_pixel()];
+        self.render_into(&mut buf, PixelFormat::Rgb8).unwrap();
+        RgbImage::from_raw(width as u32, height as u32, buf).unwrap()
+    }
+
+    /// Render where the agents of a population are right now, as opposed to the trail they left
+    /// behind: the number of agents in every cell is tonemapped like a trail map and drawn in the
+    /// color of the population. The white point is at least one agent, so sparse populations show
+    /// every agent.
+    pub fn render_density(&self, population_id: usize) -> RgbImage {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let mut counts = vec![0.0_f32; width * height];
+        for agent in &self.agents {
+            if agent.population_id == population_id {
+                let i = (agent.x as usize).min(width - 1);
+                let j = (agent.y as usize).min(height - 1);
+                counts[j * width + i] += 1.0;
+            }
+        }
+
+        let mut sorted = counts.clone();
+        let index = ((counts.len() as f32 * WHITE_POINT_QUANTILE) as usize).min(counts.len() - 1);
+        let (_, quantile, _) = sorted.select_nth_unstable_by(index, f32::total_cmp);
+        let white_point = (*quantile * WHITE_POINT_SCALE).max(1.0);
+
+        let palette = self.current_palette();
+        let colors = palette.colors();
+        let color = colors[population_id % colors.len()].0;
+        let tonemap = self.tonemaps[population_id];
+        RgbImage::from_fn(width as u32, height as u32, |x, y| {
+            let t = tonemap.intensity(counts[y as usize * width + x as usize], white_point);
+            Rgb(color.map(|c| (c as f32 * t) as u8))
+        })
+    }
+
+    /// Render the current state downscaled so that its larger dimension is at most max_dim.
+    pub fn render_thumbnail(&self, max_dim: u32) -> RgbImage {
+        let img = self.render();
+        let (width, height) = img.dimensions();
+        let scale = (max_dim as f32 / width.max(height) as f32).min(1.0);
+        let (new_width, new_height) = (
+            ((width as f32 * scale) as u32).max(1),
+            ((height as f32 * scale) as u32).max(1),
+        );
+        imageops::resize(&img, new_width, new_height, FilterType::Lanczos3)
+    }
+
+    /// Frame the images written by save_to_image to a rectangle of the grid. The rectangle wraps
+    /// around the grid edges, so it may straddle them to keep a cluster away from the seam. The
+    /// simulation itself always runs on the whole grid.
+    pub fn set_render_viewport(&mut self, x0: usize, y0: usize, width: usize, height: usize) {
+        self.viewport = Some((x0, y0, width, height));
+    }
+
+    /// Go back to saving the whole grid.
+    pub fn reset_render_viewport(&mut self) {
+        self.viewport = None;
+    }
+
+    /// Center the images written by save_to_image on the centroid of the trails, so that the
+    /// densest region sits in the middle rather than being cut by the grid edges. The size of the
+    /// render viewport is kept if one is set, its position is replaced.
+    pub fn set_recenter(&mut self, enable: bool) {
+        self.recenter = enable;
+    }
+
+    /// Centroid of the summed trails of all populations. The grid is a torus, so each coordinate
+    /// is the circular mean of the cell positions weighted by the trail, which doesn't depend on
+    /// where the grid is cut. An empty grid has its centroid at the center.
+    pub fn trail_centroid(&self) -> (f32, f32) {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        let (mut columns, mut rows) = (vec![0.0_f64; width], vec![0.0_f64; height]);
+        for grid in &self.grids {
+            for (row, values) in rows.iter_mut().zip(grid.data().chunks_exact(width)) {
+                for (column, &value) in columns.iter_mut().zip(values) {
+                    *column += value as f64;
+                    *row += value as f64;
+                }
+            }
+        }
+        let circular_mean = |weights: &[f64]| {
+            let n = weights.len() as f64;
+            let (mut c, mut s) = (0.0, 0.0);
+            for (k, w) in weights.iter().enumerate() {
+                let theta = std::f64::consts::TAU * k as f64 / n;
+                c += w * theta.cos();
+                s += w * theta.sin();
+            }
+            if c.hypot(s) <= f64::EPSILON {
+                return (n / 2.0) as f32;
+            }
+            ((s.atan2(c) / std::f64::consts::TAU).rem_euclid(1.0) * n) as f32
+        };
+        (circular_mean(&columns), circular_mean(&rows))
+    }
+
+    /// Rectangle of the grid written by save_to_image, given the viewport and recentering.
+    #[cfg(feature = "native")]
+    fn render_window(&self) -> (usize, usize, usize, usize) {
+        let (grid_width, grid_height) = (self.grids[0].width, self.grids[0].height);
+        let (x0, y0, width, height) = self.viewport.unwrap_or((0, 0, grid_width, grid_height));
+        if !self.recenter {
+            return (x0, y0, width, height);
+        }
+        let (cx, cy) = self.trail_centroid();
+        let origin = |c: f32, size: usize, n: usize| {
+            (c.round() as isize - (size / 2) as isize).rem_euclid(n as isize) as usize
+        };
+        (
+            origin(cx, width, grid_width),
+            origin(cy, height, grid_height),
+            width,
+            height,
+        )
+    }
+
+    /// Render a rectangle of the grid, wrapping around its edges. White points are computed over
+    /// the whole grid, so the colors match a full render.
+    pub fn render_viewport(&self, x0: usize, y0: usize, width: usize, height: usize) -> RgbImage {
+        let (grid_width, grid_height) = (self.grids[0].width, self.grids[0].height);
+        let max_values = self.with_pool(|| self.white_points());
+        let palette = self.current_palette();
+        RgbImage::from_fn(width as u32, height as u32, |x, y| {
+            let i = (x0 + x as usize) % grid_width;
+            let j = (y0 + y as usize) % grid_height;
+            Rgb(self.tonemap(j * grid_width + i, &max_values, &palette))
+        })
+    }
+
+    /// Render a window of the grid like render_viewport, with the summed trail intensity of all
+    /// populations as alpha, so that empty cells are transparent and the image can be overlaid on
+    /// other content.
+    pub fn render_viewport_rgba(
+        &self,
+        x0: usize,
+        y0: usize,
+        width: usize,
+        height: usize,
+    ) -> RgbaImage {
+        let (grid_width, grid_height) = (self.grids[0].width, self.grids[0].height);
+        let max_values = self.with_pool(|| self.white_points());
+        let palette = self.current_palette();
+        RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+            let i = (x0 + x as usize) % grid_width;
+            let j = (y0 + y as usize) % grid_height;
+            Rgba(self.tonemap_rgba(j * grid_width + i, &max_values, &palette))
+        })
+    }
+
+    /// Render the whole grid with the summed trail intensity as alpha.
+    pub fn render_rgba(&self) -> RgbaImage {
+        let (width, height) = (self.grids[0].width, self.grids[0].height);
+        self.render_viewport_rgba(0, 0, width, height)
+    }
+
+    /// Make the images written by render_current_frame and save_to_image RGBA, with alpha derived
+    /// from the trail intensity, instead of opaque RGB. The output format must support an alpha
+    /// channel, such as PNG. Frames written by run and save_frame and captured frames are always
+    /// opaque RGB, see ImgData; use render_rgba to get the alpha of a frame in memory.
+    pub fn set_output_alpha(&mut self, enable: bool) {
+        self.output_alpha = enable;
+    }
+
+    /// Write PNG images from save_to_image and render_current_frame in horizontal strips of the
+    /// given number of rows, or whole with None, the default. Each strip is encoded as soon as it
+    /// is rendered, so only one strip of pixels is held in memory, which allows images larger
+    /// than the available memory. The white points are still computed once for the whole grid.
+    /// Other image formats are always rendered whole.
+    pub fn set_strip_height(&mut self, rows: Option<usize>) {
+        self.strip_height = rows.map(|rows| rows.max(1));
+    }
+
+    /// Render a window of the grid strip by strip and stream the rows to a PNG encoder.
+    #[cfg(feature = "native")]
+    fn write_png_strips<P: AsRef<Path>>(
+        &self,
+        path: P,
+        (x0, y0, width, height): (usize, usize, usize, usize),
+        strip_height: usize,
+    ) -> Result<(), Error> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+        let bytes_per_pixel = if self.output_alpha {
+            encoder.set_color(png::ColorType::RGBA);
+            4
+        } else {
+            encoder.set_color(png::ColorType::RGB);
+            3
+        };
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut stream = writer.stream_writer();
+
+        let (grid_width, grid_height) = (self.grids[0].width, self.grids[0].height);
+        let max_values = self.white_points();
+        let palette = self.current_palette();
+        let mut strip = vec![0; width * strip_height * bytes_per_pixel];
+        for y in (0..height).step_by(strip_height) {
+            let rows = strip_height.min(height - y);
+            let strip = &mut strip[..width * rows * bytes_per_pixel];
+            strip
+                .par_chunks_exact_mut(bytes_per_pixel)
+                .enumerate()
+                .for_each(|(k, pixel)| {
+                    let i = (x0 + k % width) % grid_width;
+                    let j = (y0 + y + k / width) % grid_height;
+                    let cell = j * grid_width + i;
+                    if self.output_alpha {
+                        pixel.copy_from_slice(&self.tonemap_rgba(cell, &max_values, &palette));
+                    } else {
+                        pixel.copy_from_slice(&self.tonemap(cell, &max_values, &palette));
+                    }
+                });
+            stream.write_all(strip)?;
+        }
+        stream.finish()?;
+        Ok(())
+    }
+
+    /// Output the current trail layer as an image, framed to the render viewport if one is set.
+    #[cfg(feature = "native")]
+    pub fn save_to_image(&self, name: &str) {
+        self.render_current_frame(name).unwrap();
+    }
+
+    /// Render the current state and write it to an image file right away, framed to the render
+    /// viewport if one is set. Unlike save_frame and save_image_data, this neither numbers the
+    /// frame nor touches the buffer of captured frames, so any single step can be grabbed.
+    #[cfg(feature = "native")]
+    pub fn render_current_frame<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.with_pool(|| self.write_frame(path))
+    }
+
+    #[cfg(feature = "native")]
+    fn write_frame(&self, path: &Path) -> Result<(), Error> {
+        let window = self.render_window();
+        if let Some(strip_height) = self.strip_height {
+            if self.frame_format.format == ImageFormat::Png {
+                return self.write_png_strips(path, window, strip_height);
+            }
+        }
+        let (x0, y0, width, height) = window;
+        let (w, h) = (width as u32, height as u32);
+        if self.output_alpha {
+            let img = self.render_viewport_rgba(x0, y0, width, height);
+            self.frame_format
+                .save_buffer(path, &img, w, h, ColorType::Rgba8)?;
+        } else {
+            let img = self.render_viewport(x0, y0, width, height);
+            self.frame_format
+                .save_buffer(path, &img, w, h, ColorType::Rgb8)?;
+        }
+        Ok(())
+    }
+
+    /// Turn the model into an iterator that advances the simulation one step at a time and yields
+    /// the rendered frame after each of the given number of steps. Nothing is written to disk, so
+    /// the caller decides what to do with every frame. The model is consumed, but it can be
+    /// recovered with Frames::into_model.
+    pub fn frames(self, steps: usize) -> Frames {
+        self.frames_with(RunConfig {
+            steps,
+            ..RunConfig::default()
+        })
+    }
+
+    /// Same as frames, but only yields the frames that the run configuration captures, i.e. it
+    /// honors the warmup and capture_every. The output settings are ignored.
+    pub fn frames_with(mut self, config: RunConfig) -> Frames {
+        self.total_iterations = Some(self.iteration + config.steps);
+        Frames {
+            model: self,
+            config,
+            step: 0,
+        }
+    }
+}
+
+/// Iterator over the rendered frames of a simulation, see Model::frames.
+pub struct Frames {
+    model: Model,
+    config: RunConfig,
+    step: usize,
+}
+
+impl Frames {
+    /// Stop iterating and take back the model in its current state.
+    pub fn into_model(self) -> Model {
+        self.model
+    }
+}
+
+impl Iterator for Frames {
+    type Item = RgbImage;
+
+    fn next(&mut self) -> Option<RgbImage> {
+        while self.step < self.config.steps {
+            self.model.step();
+            self.step += 1;
+            if self.config.captures(self.step - 1) && !self.model.in_warmup() {
+                return Some(self.model.render());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{grid::ParamDistribution, palette::random_palette, util::wrap};
+    use image::{GrayImage, Luma};
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn test_disk_placement() {
+        let (cx, cy, r) = (32.0, 20.0, 10.0);
+        let placement = InitialPlacement::Disk { cx, cy, r };
+        let model = Model::new_with_placement(64, 64, 1000, 2, 1.0, placement).unwrap();
+        assert_eq!(model.agents.len(), 1000);
+        for agent in &model.agents {
+            let (dx, dy) = (agent.x - cx, agent.y - cy);
+            assert!((dx * dx + dy * dy).sqrt() <= r + 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_new_with_density() {
+        let model = Model::new_with_density(32, 16, 1.5, 2, 1.0).unwrap();
+        assert_eq!(model.agents.len(), 768);
+        let model = Model::new_with_density(64, 32, 1.5, 2, 1.0).unwrap();
+        assert_eq!(model.agents.len(), 4 * 768);
+    }
+
+    #[test]
+    fn test_preview_clone_at() {
+        let mut model = ModelBuilder::new()
+            .size(64, 32)
+            .particles(1600)
+            .populations(2)
+            .diffusivity(4.0)
+            .seed(9)
+            .build()
+            .unwrap();
+        model.grids[1].config.speed_jitter = 0.1;
+        let preview = model.preview_clone_at(16).unwrap();
+
+        assert_eq!((preview.grids[0].width, preview.grids[0].height), (16, 8));
+        assert_eq!(preview.agents.len(), 100);
+        assert_eq!(preview.seed(), 9);
+        assert_eq!(preview.palette.colors, model.palette.colors);
+        assert_eq!(preview.attraction_table, model.attraction_table);
+        assert_eq!(preview.diffusivity, 1.0);
+        for (small, large) in preview.grids.iter().zip(&model.grids) {
+            assert_eq!(
+                small.config.sensor_distance,
+                large.config.sensor_distance / 4.0
+            );
+            assert_eq!(small.config.step_distance, large.config.step_distance / 4.0);
+            assert_eq!(small.config.sensor_angle, large.config.sensor_angle);
+            assert_eq!(small.config.decay_factor, large.config.decay_factor);
+        }
+        assert_eq!(preview.grids[1].config.speed_jitter, 0.1);
+
+        assert!(model.preview_clone_at(10).is_err());
+    }
+
+    #[test]
+    fn test_zero_blur_radius() {
+        let builder = ModelBuilder::new().size(32, 32).particles(100);
+        assert_eq!(
+            builder.clone().diffusivity(0.5).build().err(),
+            Some(Error::ZeroBlurRadius(0.5))
+        );
+        assert_eq!(
+            Model::new(32, 32, 100, 1, 0.5).err(),
+            Some(Error::ZeroBlurRadius(0.5))
+        );
+        let mut model = builder.diffusivity(1.0).build().unwrap();
+        assert_eq!(model.set_diffusivity(0.0), Err(Error::ZeroBlurRadius(0.0)));
+        for &diffusivity in &[f32::NAN, f32::INFINITY, -2.0] {
+            assert!(matches!(
+                model.set_diffusivity(diffusivity),
+                Err(Error::InvalidDiffusivity(_))
+            ));
+        }
+        assert!(matches!(
+            ModelBuilder::new().diffusivity(f32::NAN).build(),
+            Err(Error::InvalidDiffusivity(_))
+        ));
+        assert_eq!(model.diffusivity, 1.0);
+
+        // Shrinking the grid shrinks the diffusivity along with it.
+        assert_eq!(model.rescale(8, 8), Err(Error::ZeroBlurRadius(0.25)));
+        assert_eq!(model.grids[0].width, 32);
+        assert!(model.preview_clone_at(8).is_err());
+        model.rescale(64, 64).unwrap();
+        assert_eq!(model.diffusivity, 2.0);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let builder = ModelBuilder::new().particles(100);
+        assert_eq!(
+            builder.clone().size(48, 64).build().err(),
+            Some(Error::InvalidGridSize {
+                width: 48,
+                height: 64
+            })
+        );
+        assert_eq!(
+            builder.clone().size(64, 64).populations(0).build().err(),
+            Some(Error::ZeroCount("populations"))
+        );
+        assert_eq!(
+            builder.clone().size(64, 64).particles(0).build().err(),
+            Some(Error::ZeroCount("particles"))
+        );
+        let mut config = PopulationConfig::new(&mut rand::thread_rng());
+        config.speed_jitter = f32::NAN;
+        assert!(matches!(
+            builder.clone().size(64, 64).configs(vec![config]).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        // Inverted sampler bounds and sampled values outside of the valid range are errors rather
+        // than panics.
+        for decay_factor in [
+            ParamDistribution::Uniform { min: 0.9, max: 0.1 },
+            ParamDistribution::Uniform { min: 2.0, max: 3.0 },
+        ]
+        .iter()
+        {
+            let sampler = ConfigSampler {
+                decay_factor: *decay_factor,
+                ..ConfigSampler::default()
+            };
+            assert!(matches!(
+                builder.clone().size(64, 64).sampler(sampler).build(),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_diffuse_substeps() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(100)
+            .build()
+            .unwrap();
+        assert_eq!(
+            model.set_diffuse_substeps(0),
+            Err(Error::ZeroCount("diffusion substeps"))
+        );
+        assert_eq!(model.diffuse_substeps, 1);
+        model.set_diffuse_substeps(4).unwrap();
+        assert_eq!(model.diffuse_substeps, 4);
+    }
+
+    #[test]
+    fn test_param_schedule() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(100)
+            .populations(2)
+            .build()
+            .unwrap();
+        let decay_factor = model.grids[0].config.decay_factor;
+        let rotation_angle = model.grids[1].config.rotation_angle;
+        model
+            .set_param_schedule(1, Param::SensorAngle, |i| 0.5 + i as f32)
+            .unwrap();
+        model
+            .set_param_schedule(1, Param::SensorAngle, |i| 0.1 * i as f32)
+            .unwrap();
+        assert_eq!(
+            model.set_param_schedule(2, Param::SensorAngle, |_| 0.0),
+            Err(Error::InvalidPopulation {
+                population_id: 2,
+                n_populations: 2
+            })
+        );
+        for i in 0..3 {
+            model.step();
+            assert_eq!(model.grids[1].config.sensor_angle, 0.1 * i as f32);
+        }
+        assert_eq!(model.grids[0].config.decay_factor, decay_factor);
+        assert_eq!(model.grids[1].config.rotation_angle, rotation_angle);
+    }
+
+    #[test]
+    fn test_population_config_mut() {
+        let build = || {
+            ModelBuilder::new()
+                .size(32, 32)
+                .particles(100)
+                .seed(3)
+                .build()
+                .unwrap()
+        };
+        let (mut model, mut reference) = (build(), build());
+        model.step();
+        reference.step();
+
+        model.population_config_mut(0).step_distance = 0.0;
+        model.population_config_mut(0).step_jitter = 0.0;
+        model.set_diffusivity(2.0).unwrap();
+        let before: Vec<_> = model.agents.iter().map(|a| (a.x, a.y)).collect();
+        model.step();
+        reference.step();
+        assert_eq!(model.population_config(0).step_distance, 0.0);
+        for (agent, &(x, y)) in model.agents.iter().zip(&before) {
+            assert_eq!((agent.x, agent.y), (x, y));
+        }
+        assert!(reference
+            .agents
+            .iter()
+            .zip(&before)
+            .any(|(agent, &(x, y))| (agent.x, agent.y) != (x, y)));
+
+        // The blur follows the new diffusivity rather than the radii cached for the old one.
+        let mut expected = reference.grids[0].clone();
+        expected.diffuse_and_decay(2.0, 1);
+        model.grids[0] = reference.grids[0].clone();
+        model.diffuse_trails();
+        assert_eq!(model.grids[0].data(), expected.data());
+    }
+
+    #[test]
+    fn test_run_headless() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(100)
+            .build()
+            .unwrap();
+        let stats = model.run_headless(5);
+        assert_eq!(model.iteration(), 5);
+        assert_eq!(stats.per_step_ms.len(), 5);
+        assert_eq!(stats.per_agent_ms.len(), 5);
+        assert!(stats.total_ms >= stats.per_step_ms.iter().sum::<f64>());
+
+        // Without agents the per-agent timings stay finite.
+        model.kill_agents(|_| true);
+        let stats = model.run_headless(2);
+        assert_eq!(stats.per_agent_ms, vec![0.0, 0.0]);
+        assert_eq!(stats.mean_agent_ms(), 0.0);
+
+        let stats = RunStats {
+            per_step_ms: vec![10.0, 1.0, 2.0, 3.0],
+            per_agent_ms: vec![0.1, 0.01, 0.02, 0.03],
+            total_ms: 16.0,
+            warmup_steps: 1,
+        };
+        assert_eq!(stats.mean_step_ms(), 2.0);
+        assert!((stats.mean_agent_ms() - 0.02).abs() < 1e-12);
+        assert_eq!(RunStats::default().mean_step_ms(), 0.0);
+    }
+
+    #[test]
+    fn test_inertia() {
+        let mut agent = Agent::new(0, 5.0, 5.0, 0.0, 1.0, 0);
+        agent.rotate_and_move(1.0, 0.4, 0.0, 0.0, 16, 16);
+        assert!((agent.angle - 0.4).abs() < 1e-6);
+
+        let mut agent = Agent::new(0, 5.0, 5.0, 0.0, 1.0, 0);
+        agent.rotate_and_move(1.0, 0.4, 0.5, 0.0, 16, 16);
+        assert!((agent.angle - 0.2).abs() < 1e-6);
+        agent.rotate_and_move(1.0, 0.4, 0.5, 0.0, 16, 16);
+        assert!((agent.angle - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_agent_speed() {
+        let mut agent = Agent::new(0, 5.0, 5.0, 0.0, 1.5, 0);
+        agent.rotate_and_move(0.0, 0.0, 0.0, 2.0, 16, 16);
+        assert!((agent.x - 8.0).abs() < 1e-6);
+        assert!((agent.y - 5.0).abs() < 1e-6);
+
+        let model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(100)
+            .build()
+            .unwrap();
+        assert!(model.agents.iter().all(|agent| agent.speed == 1.0));
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(200)
+            .populations(2)
+            .build()
+            .unwrap();
+        let lifecycle = Lifecycle {
+            energy_gain: 0.0,
+            energy_loss: 1.0,
+            split_threshold: 1.5,
+            initial_energy: 3.0,
+        };
+        model.set_lifecycle(0, Some(lifecycle));
+
+        // Every agent of the first population splits and then starves.
+        model.step();
+        assert_eq!(model.agents.len(), 300);
+        model.step();
+        assert_eq!(model.agents.len(), 100);
+        assert!(model.agents.iter().all(|agent| agent.population_id == 1));
+    }
+
+    #[test]
+    fn test_builder_lifecycle() {
+        let lifecycle = Lifecycle {
+            energy_gain: 0.0,
+            energy_loss: 1.0,
+            split_threshold: 10.0,
+            initial_energy: 3.0,
+        };
+        let mut config = PopulationConfig::from_params(10.0, 1.0, 0.5, 0.3, 0.1, 5.0).unwrap();
+        config.lifecycle = Some(lifecycle);
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(100)
+            .configs(vec![config])
+            .build()
+            .unwrap();
+        assert!(model.agents.iter().all(|agent| agent.energy == 3.0));
+
+        // The agents start with their initial energy rather than starving on the first step.
+        model.step();
+        assert_eq!(model.agents.len(), 100);
+        assert!(model.agents.iter().all(|agent| agent.energy == 2.0));
+    }
+
+    #[test]
+    fn test_reassign_populations() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(10)
+            .populations(2)
+            .build()
+            .unwrap();
+        assert_eq!(model.population_counts(), vec![5, 5]);
+
+        let (x, y) = (model.agents[0].x, model.agents[0].y);
+        model
+            .reassign_populations(|agent| {
+                if (agent.x, agent.y) == (x, y) {
+                    Some(1 - agent.population_id)
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+        assert_eq!(model.agents[0].population_id, 1);
+        assert_eq!(model.population_counts(), vec![4, 6]);
+
+        // Once everyone is moved, nothing deposits on the first grid anymore.
+        // An invalid id anywhere leaves every agent where it was.
+        assert_eq!(
+            model.reassign_populations(|agent| Some(if agent.population_id == 0 { 1 } else { 2 })),
+            Err(Error::InvalidPopulation {
+                population_id: 2,
+                n_populations: 2
+            })
+        );
+        assert_eq!(model.population_counts(), vec![4, 6]);
+        assert_eq!(model.agents[0].population_id, 1);
+
+        model
+            .reassign_populations(|agent| (agent.trail(1) >= 0.0).then_some(1))
+            .unwrap();
+        assert_eq!(model.population_counts(), vec![0, 10]);
+        model.grids[0].config.deposition_amount = 1000.0;
+        model.step();
+        assert!(model.grids[0].data().iter().all(|&value| value < 1.0));
+    }
+
+    #[test]
+    fn test_step_order() {
+        use Phase::*;
+        assert_eq!(
+            StepOrder::new([Combine, Move, Move, Diffuse]),
+            Err(Error::InvalidStepOrder)
+        );
+
+        // Depositing after diffusion leaves the fresh deposit unblurred.
+        let max_after_step = |order| {
+            let mut model = ModelBuilder::new()
+                .size(16, 16)
+                .particles(1)
+                .seed(3)
+                .build()
+                .unwrap();
+            model.grids[0].config.deposition_amount = 100.0;
+            model.grids[0].config.decay_factor = 1.0;
+            model.set_step_order(StepOrder::new(order).unwrap());
+            model.step();
+            model.grids[0].data().iter().cloned().fold(0.0, f32::max)
+        };
+        assert!(max_after_step([Combine, Move, Diffuse, Deposit]) > 100.0);
+        assert!(max_after_step([Combine, Move, Deposit, Diffuse]) < 100.0);
+    }
+
+    #[test]
+    fn test_deposit_threshold() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(200)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.grids[1].config.deposit_threshold = 1e9;
+        for grid in &mut model.grids {
+            grid.config.decay_factor = 1.0;
+        }
+        let sums: Vec<_> = model.grids.iter().map(|grid| grid.sum()).collect();
+        model.step();
+        model.step();
+
+        // Agents of the second population never sense enough to deposit.
+        assert!(model.grids[0].sum() > sums[0] + 100.0);
+        assert!((model.grids[1].sum() - sums[1]).abs() < 1e-2 * sums[1]);
+        assert!(model.agents.iter().all(|agent| agent.sensed.is_finite()));
+    }
+
+    #[test]
+    fn test_smooth_deposit() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(50)
+            .build()
+            .unwrap();
+        model.set_smooth_deposit(true);
+        model.set_step_order(
+            StepOrder::new([Phase::Combine, Phase::Move, Phase::Diffuse, Phase::Deposit]).unwrap(),
+        );
+        model.grids[0].config.decay_factor = 1.0;
+        let mass = |model: &Model| model.grids[0].data().iter().sum::<f32>();
+        let before = mass(&model);
+        model.step();
+        let deposited = 50.0 * model.grids[0].config.deposition_amount;
+        assert!((mass(&model) - before - deposited).abs() < 1e-3 * deposited.max(1.0));
+    }
+
+    #[test]
+    fn test_agent_order_independence() {
+        let grids_after_step = |reorder: bool| {
+            let mut model = ModelBuilder::new()
+                .size(16, 16)
+                .particles(500)
+                .populations(2)
+                .seed(5)
+                .build()
+                .unwrap();
+            model.grids[0].config.deposit_saturation = 0.5;
+            if reorder {
+                model.agents.reverse();
+            }
+            model.step();
+            model
+                .grids
+                .iter()
+                .map(|grid| grid.data().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(grids_after_step(false), grids_after_step(true));
+    }
+
+    #[test]
+    fn test_local_density() {
+        // A cluster straddling the corner of the grid.
+        let placement = InitialPlacement::Disk {
+            cx: 0.0,
+            cy: 0.0,
+            r: 2.0,
+        };
+        let mut model = Model::new_with_placement(64, 64, 200, 1, 1.0, placement).unwrap();
+        assert_eq!(model.local_density(0.0, 0.0, 2.5), 200);
+        assert_eq!(model.local_density(63.0, 63.0, 4.5), 200);
+        assert_eq!(model.local_density(32.0, 32.0, 10.0), 0);
+
+        model.set_spatial_index(Some(4.0));
+        assert_eq!(model.local_density(0.0, 0.0, 2.5), 200);
+        assert_eq!(model.local_density(32.0, 32.0, 10.0), 0);
+
+        // The index follows the agents.
+        model.step();
+        let expected = model
+            .agents
+            .iter()
+            .filter(|agent| {
+                let dx = agent.x.min(64.0 - agent.x);
+                let dy = agent.y.min(64.0 - agent.y);
+                dx * dx + dy * dy <= 9.0
+            })
+            .count();
+        assert_eq!(model.local_density(0.0, 0.0, 3.0), expected);
+    }
+
+    #[test]
+    fn test_flow_field() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(4)
+            .populations(2)
+            .build()
+            .unwrap();
+        let positions = [
+            (3.5, 2.5, 0.0),
+            (3.2, 2.9, FRAC_PI_2),
+            (9.5, 9.5, PI),
+            (3.5, 2.5, PI),
+        ];
+        for (agent, &(x, y, angle)) in model.agents.iter_mut().zip(&positions) {
+            agent.x = x;
+            agent.y = y;
+            agent.angle = angle;
+        }
+
+        let field = model.flow_field(0);
+        let (cos, sin) = field[2 * 16 + 3];
+        assert!((cos - 0.5).abs() < 1e-6 && (sin - 0.5).abs() < 1e-6);
+        assert!(field
+            .iter()
+            .enumerate()
+            .all(|(k, &mean)| k == 2 * 16 + 3 || mean == (0.0, 0.0)));
+
+        let img = model.render_flow_field(1);
+        assert_eq!(img.dimensions(), (16, 16));
+        assert_eq!(img.get_pixel(3, 2), &Rgb([0, 255, 255]));
+        assert_eq!(img.get_pixel(9, 9), &Rgb([0, 255, 255]));
+        assert_eq!(img.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_render_density() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(6)
+            .populations(2)
+            .palette(Palette {
+                colors: [Rgb([200, 100, 0]); 5],
+            })
+            .build()
+            .unwrap();
+        let positions = [
+            (1.5, 1.5),
+            (1.5, 1.5),
+            (4.5, 1.5),
+            (8.5, 8.5),
+            (8.5, 8.5),
+            (8.5, 8.5),
+        ];
+        for (agent, &(x, y)) in model.agents.iter_mut().zip(&positions) {
+            agent.x = x;
+            agent.y = y;
+        }
+
+        // On a small grid the white point quantile is the largest count.
+        let shade = |count: f32, max: f32| {
+            let t = render::intensity(count, max * WHITE_POINT_SCALE);
+            Rgb([(200.0 * t) as u8, (100.0 * t) as u8, 0])
+        };
+        let img = model.render_density(0);
+        assert_eq!(img.dimensions(), (16, 16));
+        assert_eq!(img.get_pixel(1, 1), &shade(2.0, 2.0));
+        assert_eq!(img.get_pixel(4, 1), &shade(1.0, 2.0));
+        assert_eq!(img.get_pixel(8, 8), &Rgb([0, 0, 0]));
+        let img = model.render_density(1);
+        assert_eq!(img.get_pixel(8, 8), &shade(3.0, 3.0));
+        assert_eq!(img.get_pixel(1, 1), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_spawn_and_kill_agents() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(200)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.run_headless(2);
+
+        let emitter = InitialPlacement::Disk {
+            cx: 8.0,
+            cy: 8.0,
+            r: 2.0,
+        };
+        model.spawn_agents(100, 1, emitter).unwrap();
+        assert_eq!(
+            model.spawn_agents(100, 2, emitter),
+            Err(Error::InvalidPopulation {
+                population_id: 2,
+                n_populations: 2
+            })
+        );
+        assert_eq!(model.agents.len(), 300);
+        assert_eq!(model.population_counts(), vec![100, 200]);
+        let mut indices: Vec<_> = model.agents.iter().map(|agent| agent.i).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        assert_eq!(indices.len(), 300);
+        model.run_headless(2);
+
+        model.kill_agents(|agent| agent.population_id == 0);
+        assert_eq!(model.population_counts(), vec![0, 200]);
+        model.run_headless(2);
+        assert_eq!(model.iteration(), 6);
+    }
+
+    #[test]
+    fn test_disk_shape() {
+        let mut model = ModelBuilder::new()
+            .size(32, 16)
+            .particles(500)
+            .shape(GridShape::Disk)
+            .build()
+            .unwrap();
+        model.grids[0].config.step_distance = 3.0;
+        model.grids[0].config.deposition_amount = 100.0;
+        let inside = |x: f32, y: f32| GridShape::Disk.contains(x, y, 32, 16);
+        for _ in 0..10 {
+            model.step();
+            assert!(model.agents.iter().all(|agent| inside(agent.x, agent.y)));
+            for (idx, value) in model.grids[0].data().iter().enumerate() {
+                let (x, y) = ((idx % 32) as f32 + 0.5, (idx / 32) as f32 + 0.5);
+                if !inside(x, y) {
+                    assert_eq!(*value, 0.0);
+                }
+            }
+        }
+        assert!(model.grids[0].data().iter().any(|&value| value > 1.0));
+    }
+
+    #[test]
+    fn test_bounce() {
+        // Heading straight out of the right side of the disk comes straight back.
+        let mut agent = Agent::new(0, 10.5, 5.0, 0.0, 1.0, 0);
+        agent.bounce((9.5, 5.0), 5.0, 5.0);
+        assert_eq!((agent.x, agent.y), (9.5, 5.0));
+        assert!((agent.angle - PI).abs() < 1e-6);
+
+        // Grazing the top of the disk keeps the tangential component.
+        let mut agent = Agent::new(0, 5.5, 10.5, FRAC_PI_2 / 2.0, 1.0, 0);
+        agent.bounce((5.0, 10.0), 5.0, 5.0);
+        let (dx, dy) = (agent.angle.cos(), agent.angle.sin());
+        assert!(dx > 0.0 && dy < 0.0);
+    }
+
+    #[test]
+    fn test_normalize_self_attraction() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        let mut matrix = model.attraction_matrix().clone();
+        matrix.set(0, 0, 1.1);
+        assert_eq!(
+            model.set_attraction_matrix(AttractionMatrix::new(2)),
+            Err(Error::GridMismatch)
+        );
+        model.set_attraction_matrix(matrix).unwrap();
+        let attraction_table = Model::sensing_attraction_table(&model.attraction_table, true);
+        combine(&mut model.grids, attraction_table.rows());
+
+        let grid = &model.grids[0];
+        for y in 0..16 {
+            for x in 0..16 {
+                let sensed = grid.get_buf(x as f32, y as f32);
+                assert_eq!(sensed, grid.data()[y * 16 + x]);
+            }
+        }
+        assert_eq!(model.attraction_table.get(0, 0), 1.1);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_thread_pool() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(100)
+            .build()
+            .unwrap();
+        model.set_thread_pool(2).unwrap();
+        model.step();
+        let pool = model.thread_pool.as_ref().unwrap();
+        assert_eq!(pool.current_num_threads(), 2);
+        assert_eq!(model.iteration, 1);
+
+        // Rendering runs on the same pool.
+        assert_eq!(model.with_pool(rayon::current_num_threads), 2);
+        let (width, height) = (32, 32);
+        let mut buf = vec![0; width * height * 3];
+        model.render_into(&mut buf, PixelFormat::Rgb8).unwrap();
+        assert_eq!(buf, model.render().into_raw());
+    }
+
+    #[test]
+    fn test_gradient_steering() {
+        assert_eq!(Model::gradient_direction(0.0, 0.0, 0.0, 0.5), 0.0);
+        assert_eq!(Model::gradient_direction(0.0, 1.0, 0.0, 0.5), 0.0);
+        assert_eq!(Model::gradient_direction(0.0, 0.0, 1.0, 0.5), 1.0);
+        assert_eq!(Model::gradient_direction(0.0, 0.0, -1.0, 0.5), -1.0);
+        let direction = Model::gradient_direction(0.1, 1.0, 0.0, 0.5);
+        assert!((direction + 0.2).abs() < 1e-6);
+        // Headings wrap around, so a gradient just across zero is a small turn.
+        let direction = Model::gradient_direction(TAU - 0.1, 1.0, 0.0, 0.5);
+        assert!((direction - 0.2).abs() < 1e-5);
+
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        model.grids[0].config.steering = Steering::Gradient;
+        model.run_headless(3);
+        assert_eq!(model.iteration(), 3);
+    }
+
+    #[test]
+    fn test_sensor_fan() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut grids = vec![Grid::new(32, 32, &mut rng)];
+        let mut field = image::GrayImage::new(32, 32);
+        field.put_pixel(21, 21, image::Luma([255]));
+        field.put_pixel(21, 10, image::Luma([128]));
+        grids[0].seed_from_image(&field);
+        combine(&mut grids, &[[1.0]]);
+
+        let grid = &mut grids[0];
+        grid.config.sensor_distance = 8.0;
+        grid.config.sensor_angle = FRAC_PI_2;
+        grid.config.n_sensors = 5;
+        let agent = Agent::new(0, 16.0, 16.0, 0.0, 1.0, 0);
+        assert_eq!(
+            Model::sense(&agent, grid, &Model::sensor_arms(&grid.config), &mut rng),
+            (0.5, 1.0)
+        );
+
+        // Three sensors only see the empty cells at the sides and ahead, and keep going straight.
+        grid.config.n_sensors = 3;
+        assert_eq!(
+            Model::sense(&agent, grid, &Model::sensor_arms(&grid.config), &mut rng),
+            (0.0, 0.0)
+        );
+
+        // A single sensor always heads straight.
+        grid.config.n_sensors = 1;
+        assert_eq!(
+            Model::sense(&agent, grid, &Model::sensor_arms(&grid.config), &mut rng),
+            (0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_sensor_arms() {
+        let mut config = PopulationConfig::new(&mut StdRng::seed_from_u64(0));
+        config.sensor_angle = 0.7;
+        for n_sensors in 1..=5 {
+            config.n_sensors = n_sensors;
+            let arms = Model::sensor_arms(&config);
+            assert_eq!(arms.len(), n_sensors);
+            for &(offset, arm_cos, arm_sin) in &arms {
+                for k in 0..64 {
+                    // Rotating the heading gives the direction of the sensor.
+                    let heading = k as f32 * TAU / 64.0;
+                    let (sin, cos) = heading.sin_cos();
+                    let angle = heading + offset * config.sensor_angle;
+                    assert!((cos * arm_cos - sin * arm_sin - angle.cos()).abs() < 1e-5);
+                    assert!((sin * arm_cos + cos * arm_sin - angle.sin()).abs() < 1e-5);
+                }
+            }
+        }
+        config.n_sensors = 3;
+        let offsets: Vec<_> = Model::sensor_arms(&config)
+            .iter()
+            .map(|arm| arm.0)
+            .collect();
+        assert_eq!(offsets, vec![-1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_deposit_position() {
+        let agent = Agent::new(0, 5.0, 5.0, FRAC_PI_2, 1.0, 0);
+        assert_eq!(agent.deposit_position(0.0), (5.0, 5.0));
+        let (x, y) = agent.deposit_position(2.0);
+        assert!((x - 5.0).abs() < 1e-6);
+        assert!((y - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_render_thumbnail() {
+        let model = ModelBuilder::new()
+            .size(64, 32)
+            .particles(100)
+            .build()
+            .unwrap();
+        assert_eq!(model.render().dimensions(), (64, 32));
+        assert_eq!(model.render_thumbnail(16).dimensions(), (16, 8));
+        assert_eq!(model.render_thumbnail(128).dimensions(), (64, 32));
+    }
+
+    #[test]
+    fn test_render_viewport() {
+        let model = ModelBuilder::new()
+            .size(16, 8)
+            .particles(100)
+            .build()
+            .unwrap();
+        let full = model.render();
+        let view = model.render_viewport(12, 6, 8, 4);
+        assert_eq!(view.dimensions(), (8, 4));
+        for (x, y, pixel) in view.enumerate_pixels() {
+            assert_eq!(pixel, full.get_pixel((12 + x) % 16, (6 + y) % 8));
+        }
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_recenter() {
+        let mut model = ModelBuilder::new()
+            .size(32, 16)
+            .particles(100)
+            .palette(random_palette())
+            .build()
+            .unwrap();
+        // A blob straddling the bottom left corner, which a plain render cuts into four pieces.
+        let blob = GrayImage::from_fn(32, 16, |x, y| {
+            let (dx, dy) = ((x as i32 + 2) % 32 - 2, (y as i32 + 3) % 16 - 3);
+            Luma([if dx * dx + dy * dy <= 4 { 255 } else { 0 }])
+        });
+        model.grids[0].seed_from_image(&blob);
+        let (cx, cy) = model.trail_centroid();
+        assert!(cx.min(32.0 - cx) < 0.1 && cy.min(16.0 - cy) < 0.1);
+
+        model.set_recenter(true);
+        assert_eq!(model.render_window(), (16, 8, 32, 16));
+        let path = std::env::temp_dir().join(format!("physarum_center_{}.png", std::process::id()));
+        model.render_current_frame(&path).unwrap();
+        let saved = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, model.render_viewport(16, 8, 32, 16));
+
+        // The centroid of the saved image is at its center.
+        let (mut mass, mut mx, mut my) = (0.0, 0.0, 0.0);
+        for (x, y, pixel) in saved.enumerate_pixels() {
+            let w: f32 = pixel.0.iter().map(|&c| c as f32).sum();
+            mass += w;
+            mx += w * x as f32;
+            my += w * y as f32;
+        }
+        assert!((mx / mass - 16.0).abs() < 0.5 && (my / mass - 8.0).abs() < 0.5);
+
+        // A viewport keeps its size and moves onto the centroid.
+        model.set_render_viewport(5, 5, 8, 4);
+        assert_eq!(model.render_window(), (28, 14, 8, 4));
+    }
+
+    #[test]
+    fn test_rescale() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(200)
+            .seed(3)
+            .palette(random_palette())
+            .build()
+            .unwrap();
+        assert_eq!(
+            model.rescale(48, 64),
+            Err(Error::InvalidGridSize {
+                width: 48,
+                height: 64
+            })
+        );
+
+        model.grids[0].seed_from_image(&GrayImage::from_pixel(32, 32, Luma([128])));
+        let level = model.grids[0].data()[0];
+        assert!(level > 0.0);
+        let positions: Vec<_> = model.agents.iter().map(|a| (a.x, a.y)).collect();
+        let sensor_distance = model.population_config(0).sensor_distance;
+        model.rescale(64, 64).unwrap();
+
+        let grid = &model.grids[0];
+        assert_eq!((grid.width, grid.height), (64, 64));
+        assert_eq!(grid.data().len(), 64 * 64);
+        assert!(grid.data().iter().all(|&v| (v - level).abs() < 1e-5));
+        for (agent, (x, y)) in model.agents.iter().zip(positions) {
+            assert!((agent.x - wrap(2.0 * x, 64.0)).abs() < 1e-4);
+            assert!((agent.y - wrap(2.0 * y, 64.0)).abs() < 1e-4);
+        }
+        let rescaled = model.population_config(0).sensor_distance;
+        assert!((rescaled - 2.0 * sensor_distance).abs() < 1e-4);
+
+        model.step();
+        assert!(model.check_finite().is_ok());
+        assert_eq!(model.render().dimensions(), (64, 64));
+    }
+
+    #[test]
+    fn test_render_into() {
+        let model = ModelBuilder::new()
+            .size(16, 8)
+            .particles(100)
+            .build()
+            .unwrap();
+        let mut buf = vec![0; 16 * 8 * 3];
+        assert_eq!(
+            model.render_into(&mut buf, PixelFormat::Rgba8),
+            Err(Error::BufferSize {
+                expected: 16 * 8 * 4,
+                actual: 16 * 8 * 3
+            })
+        );
+
+        let mut buf = vec![0; 16 * 8 * 4];
+        model.render_into(&mut buf, PixelFormat::Rgba8).unwrap();
+        let img = model.render();
+        for (rgba, rgb) in buf.chunks_exact(4).zip(img.pixels()) {
+            assert_eq!(&rgba[..3], &rgb.0);
+            assert_eq!(rgba[3], 255);
+        }
+    }
+
+    #[test]
+    fn test_step_jitter_reproducibility() {
+        let build = || {
+            let mut model = ModelBuilder::new()
+                .size(32, 32)
+                .particles(200)
+                .populations(2)
+                .seed(7)
+                .build()
+                .unwrap();
+            for grid in &mut model.grids {
+                grid.config.step_jitter = 0.5;
+            }
+            model
+        };
+        let (mut model1, mut model2) = (build(), build());
+        model1.run_headless(3);
+        model2.run_headless(3);
+        for (a1, a2) in model1.agents.iter().zip(&model2.agents) {
+            assert_eq!((a1.x, a1.y, a1.angle), (a2.x, a2.y, a2.angle));
+        }
+        for (g1, g2) in model1.grids.iter().zip(&model2.grids) {
+            assert_eq!(g1.data(), g2.data());
+        }
+    }
+
+    #[test]
+    fn test_run_config_captures() {
+        let config = RunConfig {
+            steps: 10,
+            capture_every: 3,
+            warmup: 4,
+            ..RunConfig::default()
+        };
+        let captured: Vec<_> = (0..10).filter(|&step| config.captures(step)).collect();
+        assert_eq!(captured, vec![6, 9]);
+
+        let config = RunConfig {
+            capture_every: 0,
+            ..config
+        };
+        let captured: Vec<_> = (0..10).filter(|&step| config.captures(step)).collect();
+        assert_eq!(captured, vec![9]);
+    }
+
+    #[test]
+    fn test_frames() {
+        let palette = random_palette();
+        let build = || {
+            ModelBuilder::new()
+                .size(16, 16)
+                .particles(100)
+                .seed(2)
+                .palette(palette)
+                .build()
+                .unwrap()
+        };
+        let mut model = build();
+        let frames: Vec<_> = build().frames(4).collect();
+        assert_eq!(frames.len(), 4);
+        for frame in &frames {
+            model.step();
+            assert_eq!(frame, &model.render());
+        }
+
+        let config = RunConfig {
+            steps: 10,
+            capture_every: 3,
+            warmup: 4,
+            ..RunConfig::default()
+        };
+        let mut frames = build().frames_with(config);
+        assert_eq!(frames.by_ref().count(), 2);
+        assert_eq!(frames.into_model().iteration(), 10);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_run() {
+        let modes = [(true, false), (false, false), (false, true)];
+        for &(buffer_frames, write_in_background) in &modes {
+            let output_dir = std::env::temp_dir().join(format!(
+                "physarum_run_{}_{}_{}",
+                std::process::id(),
+                buffer_frames,
+                write_in_background
+            ));
+            let mut model = ModelBuilder::new()
+                .size(16, 16)
+                .particles(100)
+                .build()
+                .unwrap();
+            let config = RunConfig {
+                steps: 5,
+                capture_every: 2,
+                warmup: 2,
+                output_dir: output_dir.clone(),
+                buffer_frames,
+                write_in_background,
+            };
+            let stats = model.run(&config).unwrap();
+            assert!(model.img_data_vec.is_empty());
+            assert_eq!(stats.per_step_ms.len(), 5);
+            assert_eq!(stats.warmup_steps, 2);
+
+            let mut frames: Vec<_> = std::fs::read_dir(&output_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            frames.sort();
+            assert_eq!(frames, vec!["out_00000002.png", "out_00000003.png"]);
+            std::fs::remove_dir_all(output_dir).unwrap();
+        }
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_image_format() {
+        let output_dir = std::env::temp_dir().join(format!("physarum_jpeg_{}", std::process::id()));
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            model.set_image_format(ImageFormat::WebP),
+            Err(Error::UnsupportedFormat(_))
+        ));
+        model.set_image_format(ImageFormat::Jpeg).unwrap();
+        assert_eq!(model.set_image_quality(0), Err(Error::InvalidQuality(0)));
+        model.set_image_quality(50).unwrap();
+        assert_eq!(model.frame_format(), FrameFormat::jpeg(50).unwrap());
+
+        let config = RunConfig {
+            steps: 2,
+            output_dir: output_dir.clone(),
+            ..RunConfig::default()
+        };
+        model.run(&config).unwrap();
+        let mut frames: Vec<_> = std::fs::read_dir(&output_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        frames.sort();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].file_name().unwrap(), "out_00000000.jpg");
+        assert_eq!(
+            image::guess_format(&std::fs::read(&frames[0]).unwrap()).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert!(image::open(&frames[1]).is_ok());
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_time_series() {
+        let output_dir =
+            std::env::temp_dir().join(format!("physarum_series_{}", std::process::id()));
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .populations(2)
+            .build()
+            .unwrap();
+        let config = RunConfig {
+            steps: 5,
+            capture_every: 2,
+            output_dir: output_dir.clone(),
+            ..RunConfig::default()
+        };
+        model.run(&config).unwrap();
+        assert_eq!(model.time_series(), TimeSeries::default());
+
+        model.set_record_time_series(true);
+        model.run(&config).unwrap();
+        let series = model.time_series();
+        assert_eq!(series.iterations, vec![7, 9, 10]);
+        assert_eq!(series.mass.len(), 2);
+        assert_eq!(series.entropy[1].len(), 3);
+        assert_eq!(series.mass[0][2], model.grids[0].sum());
+
+        let csv = series.to_csv();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "iteration,mass_0,entropy_0,mass_1,entropy_1");
+        assert!(lines[3].starts_with("10,"));
+        assert_eq!(lines[3].split(',').count(), 5);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_palette_schedule() {
+        let start = Palette {
+            colors: [Rgb([0, 0, 255]); 5],
+        };
+        let end = Palette {
+            colors: [Rgb([255, 0, 0]); 5],
+        };
+        let model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        let mut frames = model.frames(4);
+        frames.model.set_palette_schedule(start, end);
+        frames.next();
+        assert_eq!(frames.model.current_palette().colors[0], Rgb([64, 0, 191]));
+        frames.by_ref().count();
+        assert_eq!(frames.model.current_palette().colors, end.colors);
+
+        // The single population is drawn in the blended color.
+        let frame = frames.model.render();
+        assert!(frame
+            .pixels()
+            .all(|pixel| pixel.0[1] == 0 && pixel.0[2] == 0));
+    }
+
+    #[test]
+    fn test_render_empty_grids() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .populations(2)
+            .build()
+            .unwrap();
+        for grid in &mut model.grids {
+            grid.seed_from_image(&GrayImage::new(16, 16));
+        }
+        assert_eq!(model.compute_white_points(), vec![0.0, 0.0]);
+        assert!(model.render().pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn test_grayscale_palette() {
+        let mut model = ModelBuilder::new()
+            .size(64, 64)
+            .particles(100)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.set_palette_schedule(random_palette(), random_palette());
+        model.set_palette(Palette::grayscale());
+        model.grids[0].seed_from_image(&GrayImage::from_fn(64, 64, |x, y| {
+            Luma([if (x, y) == (3, 5) { 255 } else { 1 }])
+        }));
+        model.grids[1].seed_from_image(&GrayImage::from_fn(64, 64, |x, _| {
+            Luma([if x < 32 { 0 } else { 1 }])
+        }));
+
+        let img = model.render();
+        assert_eq!(img.get_pixel(3, 5).0, [255, 255, 255]);
+        // Elsewhere the gray level is the summed intensity of the populations.
+        let white_points = model.compute_white_points();
+        let t0 = render::intensity(1.0 / 255.0, white_points[0]);
+        let t1 = render::intensity(1.0 / 255.0, white_points[1]);
+        let gray = |t: f32| (255.0 * t).clamp(0.0, 255.0) as u8;
+        assert_eq!(img.get_pixel(10, 10).0, [gray(t0); 3]);
+        assert_eq!(img.get_pixel(40, 10).0, [gray(t0 + t1); 3]);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_strip_rendering() {
+        let mut model = ModelBuilder::new()
+            .size(32, 16)
+            .particles(200)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.run_headless(3);
+        let path = std::env::temp_dir().join(format!("physarum_strips_{}.png", std::process::id()));
+        // Strips that don't divide the height evenly, with and without alpha and a viewport.
+        model.set_strip_height(Some(5));
+        model.render_current_frame(&path).unwrap();
+        assert_eq!(image::open(&path).unwrap().to_rgb8(), model.render());
+
+        model.set_output_alpha(true);
+        model.set_render_viewport(20, 10, 24, 12);
+        model.render_current_frame(&path).unwrap();
+        let saved = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, model.render_viewport_rgba(20, 10, 24, 12));
+    }
+
+    #[test]
+    fn test_population_tonemap() {
+        let mut model = ModelBuilder::new()
+            .size(64, 64)
+            .particles(100)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.set_palette(Palette::grayscale());
+        for grid in &mut model.grids {
+            grid.seed_from_image(&GrayImage::from_fn(64, 64, |x, _| Luma([x as u8])));
+        }
+        let reference = model.render();
+
+        let flat = TonemapConfig {
+            gamma: 1.0,
+            ..TonemapConfig::default()
+        };
+        model.set_tonemap(flat);
+        model.set_population_tonemap(1, TonemapConfig { gain: 0.0, ..flat });
+        let img = model.render();
+        let white_points = model.compute_white_points();
+        for x in [0, 20, 40, 63] {
+            let expected = 255.0 * flat.intensity(x as f32 / 255.0, white_points[0]);
+            assert_eq!(img.get_pixel(x, 0).0, [expected as u8; 3]);
+        }
+        assert!(img.get_pixel(20, 0).0[0] < reference.get_pixel(20, 0).0[0]);
+
+        model.set_tonemap(TonemapConfig::default());
+        assert_eq!(model.render(), reference);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_render_current_frame() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        model.run_headless(3);
+        let path = std::env::temp_dir().join(format!("physarum_frame_{}.png", std::process::id()));
+        model.render_current_frame(&path).unwrap();
+        let saved = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, model.render());
+        assert_eq!(model.frame_counter, 0);
+        assert!(model.img_data_vec.is_empty());
+
+        let missing = std::env::temp_dir()
+            .join("physarum_missing_dir")
+            .join("frame.png");
+        assert!(matches!(
+            model.render_current_frame(missing),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_output_alpha() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .palette(random_palette())
+            .build()
+            .unwrap();
+        for grid in &mut model.grids {
+            grid.seed_from_image(&GrayImage::new(16, 16));
+        }
+        model.grids[0].seed_from_image(&GrayImage::from_fn(16, 16, |x, _| Luma([(x * 16) as u8])));
+
+        let rgba = model.render_rgba();
+        let rgb = model.render();
+        assert_eq!(rgba.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        // Alpha follows the intensity of the trail.
+        let alphas: Vec<u8> = (0..16).map(|x| rgba.get_pixel(x, 0).0[3]).collect();
+        assert!(alphas.windows(2).all(|w| w[0] <= w[1]));
+        assert!(alphas[15] > 0);
+        // Compositing over black gives back the opaque render.
+        for (a, b) in rgba.pixels().zip(rgb.pixels()) {
+            let alpha = a.0[3] as f32 / 255.0;
+            for c in 0..3 {
+                assert!((a.0[c] as f32 * alpha - b.0[c] as f32).abs() <= 2.0);
+            }
+        }
+
+        model.set_output_alpha(true);
+        let path = std::env::temp_dir().join(format!("physarum_alpha_{}.png", std::process::id()));
+        model.save_to_image(path.to_str().unwrap());
+        let saved = image::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.to_rgba8(), rgba);
+    }
+
+    #[test]
+    fn test_save_image_data() {
+        let mut model = ModelBuilder::new()
+            .size(16, 8)
+            .particles(100)
+            .build()
+            .unwrap();
+        model.step();
+        model.save_image_data();
+        let frame = &model.img_data_vec[0];
+        assert_eq!(frame.iteration, 1);
+        assert_eq!(frame.frame, 0);
+        assert_eq!(frame.size_in_bytes(), 16 * 8 * 3);
+        assert_eq!(frame.to_image(), model.render());
+    }
+
+    #[test]
+    fn test_warmup() {
+        let build = || {
+            ModelBuilder::new()
+                .size(16, 8)
+                .particles(100)
+                .seed(4)
+                .build()
+                .unwrap()
+        };
+        let mut model = build();
+        model.set_warmup(3);
+        for _ in 0..3 {
+            model.save_image_data();
+            model.step();
+        }
+        assert!(model.img_data_vec.is_empty());
+        // The state still advanced normally during the warmup.
+        let mut reference = build();
+        reference.run_headless(3);
+        assert_eq!(model.grids[0].data(), reference.grids[0].data());
+
+        model.save_image_data();
+        model.step();
+        model.save_image_data();
+        let frames: Vec<_> = model
+            .img_data_vec
+            .iter()
+            .map(|frame| (frame.iteration, frame.frame))
+            .collect();
+        assert_eq!(frames, vec![(3, 3), (4, 4)]);
+
+        let mut model = build();
+        model.set_warmup(3);
+        assert_eq!(model.frames(5).count(), 3);
+    }
+
+    #[test]
+    fn test_snapshot_grids() {
+        let build = || {
+            let mut model = ModelBuilder::new()
+                .size(32, 32)
+                .particles(500)
+                .populations(2)
+                .seed(5)
+                .build()
+                .unwrap();
+            model.run_headless(5);
+            model
+        };
+        let (mut model1, mut model2) = (build(), build());
+        let snapshot = model1.snapshot_grids();
+
+        for _ in 0..10 {
+            model2.grids[1].deposit(3.0, 4.0);
+        }
+        model2.restore_grids(snapshot).unwrap();
+        model1.step();
+        model2.step();
+        for (g1, g2) in model1.grids.iter().zip(&model2.grids) {
+            assert_eq!(g1.data(), g2.data());
+        }
+
+        let mut snapshot = model1.snapshot_grids();
+        snapshot.pop();
+        assert_eq!(model1.restore_grids(snapshot), Err(Error::GridMismatch));
+        let small = ModelBuilder::new()
+            .size(16, 16)
+            .particles(10)
+            .populations(2)
+            .build()
+            .unwrap();
+        assert_eq!(
+            model1.restore_grids(small.snapshot_grids()),
+            Err(Error::GridMismatch)
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_check_finite() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(200)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.step();
+        assert_eq!(model.check_finite(), Ok(()));
+
+        model.grids[1].config.deposition_amount = f32::NAN;
+        model.set_check_finite(true);
+        let output_dir =
+            std::env::temp_dir().join(format!("physarum_finite_{}", std::process::id()));
+        let config = RunConfig {
+            steps: 5,
+            output_dir: output_dir.clone(),
+            ..RunConfig::default()
+        };
+        let result = model.run(&config);
+        std::fs::remove_dir_all(output_dir).unwrap();
+        assert_eq!(
+            result.unwrap_err(),
+            Error::NonFinite {
+                population_id: 1,
+                iteration: 2
+            }
+        );
+
+        // Rendering the broken grids doesn't panic.
+        model.render();
+    }
+
+    #[test]
+    fn test_model_max_value_cap() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(2000)
+            .populations(2)
+            .build()
+            .unwrap();
+        model.set_max_value_cap(1, Some(0.5));
+        for _ in 0..5 {
+            model.step();
+            assert!(model.grids[1].data().iter().all(|&value| value <= 0.5));
+        }
+    }
+
+    #[test]
+    fn test_clamp_values() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(2000)
+            .populations(2)
+            .build()
+            .unwrap();
+        for grid in &mut model.grids {
+            grid.config.decay_factor = 1.0;
+            grid.config.deposition_amount = f32::MAX;
+        }
+        model.set_clamp_values(Some(100.0));
+        for _ in 0..3 {
+            model.step();
+        }
+        for grid in &model.grids {
+            assert!(grid.data().iter().all(|&value| value <= 100.0));
+        }
+        model.render();
+    }
+
+    #[test]
+    fn test_white_point_cache() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        let white_points = model.white_points();
+        assert_eq!(model.white_points(), white_points);
+
+        model.step();
+        let cached = model.white_point_cache.lock().unwrap().clone();
+        assert_eq!(cached.unwrap().0, 0);
+        assert_eq!(model.white_points(), model.compute_white_points());
+        let cached = model.white_point_cache.lock().unwrap().clone();
+        assert_eq!(cached.unwrap().0, 1);
+
+        model.set_approximate_quantile(true);
+        assert!(model.white_point_cache.lock().unwrap().is_none());
+
+        model.white_points();
+        model.set_max_value_cap(0, Some(1.0));
+        assert!(model.white_point_cache.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_white_point_smoothing() {
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        model.set_white_point_smoothing(Some(0.25));
+        model.grids[0].seed_from_image(&GrayImage::from_pixel(16, 16, Luma([100])));
+        let white_point = model.white_points()[0];
+        for _ in 0..5 {
+            model.iteration += 1;
+            assert_eq!(model.white_points()[0], white_point);
+        }
+
+        // A sudden change of the field only moves the white point by a quarter of the way.
+        model.grids[0].seed_from_image(&GrayImage::from_pixel(16, 16, Luma([200])));
+        model.iteration += 1;
+        let target = model.compute_white_points()[0];
+        let expected = white_point + 0.25 * (target - white_point);
+        assert!((model.white_points()[0] - expected).abs() < 1e-6);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_frame_memory_cap() {
+        let output_dir = std::env::temp_dir().join(format!("physarum_cap_{}", std::process::id()));
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let mut model = ModelBuilder::new()
+            .size(16, 16)
+            .particles(100)
+            .build()
+            .unwrap();
+        model.set_frame_memory_cap(2 * 16 * 16 * 3);
+
+        for n_buffered in &[1, 2, 0, 1] {
+            model.step();
+            model.capture_frame(&output_dir).unwrap();
+            assert_eq!(model.img_data_vec.len(), *n_buffered);
+        }
+        model.flush_image_data(&output_dir).unwrap();
+
+        // Frame numbers keep increasing across flushes.
+        let mut frames: Vec<_> = std::fs::read_dir(&output_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        frames.sort();
+        let expected: Vec<_> = (0..4).map(ImgData::file_name).collect();
+        assert_eq!(frames, expected);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_turn_randomness() {
+        let mut model = ModelBuilder::new()
+            .size(32, 32)
+            .particles(200)
+            .seed(5)
+            .build()
+            .unwrap();
+        let config = model.population_config(0).clone();
+        assert_eq!((config.turn_randomness, config.tie_break_bias), (0.0, 0.5));
+        model.grids[0].seed_from_image(&GrayImage::from_fn(32, 32, |x, y| {
+            Luma([((x * 37 + y * 11) % 256) as u8])
+        }));
+        combine(&mut model.grids, &[vec![1.0]]);
+
+        // Without randomness, directions follow the sensors and ties are a fair coin flip, drawn
+        // from the agent generator exactly as before these options existed.
+        let grid = &model.grids[0];
+        let arms = Model::sensor_arms(&grid.config);
+        for (i, agent) in model.agents.iter().enumerate() {
+            let sensor = |offset: f32| {
+                let angle = agent.angle + offset * config.sensor_angle;
+                grid.get_buf(
+                    agent.x + angle.cos() * config.sensor_distance,
+                    agent.y + angle.sin() * config.sensor_distance,
+                )
+            };
+            let (c, l, r) = (sensor(0.0), sensor(-1.0), sensor(1.0));
+            let mut rng = agent_rng(5, i, 0);
+            let expected = if c > l && c > r {
+                0.0
+            } else if c < l && c < r {
+                *[-1.0, 1.0].choose(&mut rng).unwrap()
+            } else if l < r {
+                1.0
+            } else if r < l {
+                -1.0
+            } else {
+                0.0
+            };
+            let (direction, _) = Model::sense(agent, grid, &arms, &mut agent_rng(5, i, 0));
+            assert_eq!(direction, expected);
+        }
+
+        // A full bias always breaks ties to the right, none to the left.
+        let mut rng = agent_rng(5, 0, 0);
+        assert!((0..32).all(|_| Model::pick_direction(0.0, 1.0, 1.0, 1.0, &mut rng) == 1.0));
+        assert!((0..32).all(|_| Model::pick_direction(0.0, 1.0, 1.0, 0.0, &mut rng) == -1.0));
+        assert_eq!(Model::pick_direction(2.0, 1.0, 1.0, 1.0, &mut rng), 0.0);
+
+        // Full randomness ignores the sensors and spreads turns over the whole range.
+        model.population_config_mut(0).turn_randomness = 1.0;
+        let grid = &model.grids[0];
+        let arms = Model::sensor_arms(&grid.config);
+        let directions: Vec<f32> = model
+            .agents
+            .iter()
+            .enumerate()
+            .map(|(i, agent)| Model::sense(agent, grid, &arms, &mut agent_rng(5, i, 0)).0)
+            .collect();
+        assert!(directions.iter().all(|d| (-1.0..=1.0).contains(d)));
+        assert!(directions.iter().any(|&d| d != 0.0 && d.abs() != 1.0));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_tie_break_reproducibility() {
+        // Both turns are taken when the center sensor is the weakest, in the same way every time.
+        let turns = |seed| {
+            (0..64)
+                .map(|i| Model::pick_direction(0.0, 1.0, 1.0, 0.5, &mut agent_rng(seed, i, 5)))
+                .collect::<Vec<_>>()
+        };
+        let turns1 = turns(3);
+        assert_eq!(turns1, turns(3));
+        assert_ne!(turns1, turns(4));
+        assert!(turns1.contains(&1.0) && turns1.contains(&-1.0));
+
+        // Runs don't depend on how the agents are split across threads.
+        let run = |threads| {
+            let mut model = ModelBuilder::new()
+                .size(32, 32)
+                .particles(500)
+                .populations(2)
+                .seed(11)
+                .build()
+                .unwrap();
+            model.set_thread_pool(threads).unwrap();
+            model.run_headless(5);
+            model
+        };
+        let (model1, model2) = (run(1), run(3));
+        assert_eq!(model1.agents, model2.agents);
+        for (g1, g2) in model1.grids.iter().zip(&model2.grids) {
+            assert_eq!(g1.data(), g2.data());
+        }
+    }
+
+    #[test]
+    fn test_new_with_configs() {
+        let config = PopulationConfig::from_params(10.0, 1.0, 0.5, 0.3, 0.1, 5.0).unwrap();
+        let model = Model::new_with_configs(32, 32, 100, vec![config.clone(); 2], 1.0).unwrap();
+        assert_eq!(model.grids.len(), 2);
+        assert_eq!(model.grids[1].config, config);
+
+        let invalid = PopulationConfig {
+            decay_factor: 1.5,
+            ..config.clone()
+        };
+        match Model::new_with_configs(32, 32, 100, vec![config, invalid], 1.0) {
+            Err(Error::InvalidConfig(err)) => assert_eq!(err.param, Param::DecayFactor),
+            _ => panic!("expected an invalid decay factor"),
+        }
+    }
+
+    #[test]
+    fn test_builder_seed() {
+        let builder = ModelBuilder::new()
+            .size(64, 32)
+            .particles(100)
+            .populations(2);
+        let model1 = builder.clone().seed(42).build().unwrap();
+        let model2 = builder.seed(42).build().unwrap();
+        assert_eq!(model1.seed(), 42);
+        assert_eq!(model1.attraction_table, model2.attraction_table);
+        assert_eq!(model1.palette.colors, model2.palette.colors);
+        for (a1, a2) in model1.agents.iter().zip(&model2.agents) {
+            assert_eq!((a1.x, a1.y, a1.angle), (a2.x, a2.y, a2.angle));
+        }
     }
 }
@@ -1,21 +1,150 @@
+use image::{Rgb, RgbImage};
 use rand::{seq::SliceRandom, thread_rng, Rng};
 
-#[derive(Clone, Copy)]
+#[cfg(feature = "native")]
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy)]
 pub struct Palette {
     pub colors: [image::Rgb<u8>; 5],
 }
 
+impl Palette {
+    pub fn colors(&self) -> &[Rgb<u8>] {
+        &self.colors
+    }
+
+    /// Palette in which every population is white, so that a rendered pixel is the summed
+    /// normalized density of all populations. This is meant for checking the tonemapping
+    /// independently of the colors.
+    pub fn grayscale() -> Palette {
+        Palette {
+            colors: [Rgb([255, 255, 255]); 5],
+        }
+    }
+
+    /// Map an intensity in [0, 1] to a color on a gradient that starts at black and runs through
+    /// the palette colors in order, interpolating linearly between neighboring stops.
+    pub fn gradient(&self, t: f32) -> Rgb<u8> {
+        let stops = self.colors.len();
+        let x = t.clamp(0.0, 1.0) * stops as f32;
+        let k = (x as usize).min(stops - 1);
+        let from = if k == 0 { [0; 3] } else { self.colors[k - 1].0 };
+        let to = self.colors[k].0;
+        let w = x - k as f32;
+        let mix = |c: usize| (from[c] as f32 + (to[c] as f32 - from[c] as f32) * w).round() as u8;
+        Rgb([mix(0), mix(1), mix(2)])
+    }
+
+    /// Blend with another palette, interpolating every channel of every color linearly from this
+    /// palette at t = 0 to the other one at t = 1.
+    pub fn lerp(&self, other: &Palette, t: f32) -> Palette {
+        let t = t.clamp(0.0, 1.0);
+        let mut colors = self.colors;
+        for (color, to) in colors.iter_mut().zip(&other.colors) {
+            for (c, &to) in color.0.iter_mut().zip(&to.0) {
+                *c = (*c as f32 + (to as f32 - *c as f32) * t).round() as u8;
+            }
+        }
+        Palette { colors }
+    }
+
+    /// Render the palette as a row of square blocks of the given size, one per color. Blocks that
+    /// are large enough are labeled with the hex code of their color.
+    pub fn swatch(&self, cell: u32) -> RgbImage {
+        let cell = cell.max(1);
+        let mut img = RgbImage::new(cell * self.colors.len() as u32, cell);
+        for (i, color) in self.colors.iter().enumerate() {
+            let x0 = i as u32 * cell;
+            for y in 0..cell {
+                for x in x0..x0 + cell {
+                    img.put_pixel(x, y, *color);
+                }
+            }
+            draw_label(&mut img, x0, cell, *color);
+        }
+        img
+    }
+
+    /// Write the palette swatch to an image file.
+    #[cfg(feature = "native")]
+    pub fn save_swatch<P: AsRef<Path>>(&self, path: P, cell: u32) -> image::ImageResult<()> {
+        self.swatch(cell).save(path)
+    }
+}
+
+/// 3x5 bitmap glyphs of the hex digits, one row per 3 bits from top to bottom.
+const HEX_GLYPHS: [u16; 16] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_001_001_001,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+    0b111_101_111_101_101,
+    0b110_101_110_101_110,
+    0b111_100_100_100_111,
+    0b110_101_101_101_110,
+    0b111_100_111_100_111,
+    0b111_100_111_100_100,
+];
+
+/// Draw the hex code of a color at the bottom of its swatch block, in black or white depending on
+/// the brightness of the color. Nothing is drawn if the block is too small for the label.
+fn draw_label(img: &mut RgbImage, x0: u32, cell: u32, color: Rgb<u8>) {
+    // Six glyphs 3 pixels wide separated by 1 pixel, with a 2 pixel margin on every side.
+    let scale = cell / 27;
+    if scale == 0 {
+        return;
+    }
+    let [r, g, b] = color.0;
+    let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+    let ink = if luma > 128.0 {
+        Rgb([0, 0, 0])
+    } else {
+        Rgb([255, 255, 255])
+    };
+
+    let digits = [r >> 4, r & 0xf, g >> 4, g & 0xf, b >> 4, b & 0xf];
+    let y0 = cell - 7 * scale;
+    for (k, digit) in digits.iter().enumerate() {
+        let glyph = HEX_GLYPHS[*digit as usize];
+        let gx = x0 + (2 + 4 * k as u32) * scale;
+        for row in 0..5 {
+            for col in 0..3 {
+                if glyph >> (14 - 3 * row - col) & 1 == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        img.put_pixel(gx + col * scale + dx, y0 + row * scale + dy, ink);
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub fn random_palette() -> Palette {
-    let mut rng = thread_rng();
+    random_palette_seeded(&mut thread_rng())
+}
+
+/// Pick a palette and shuffle its colors with the given random number generator, so that a seeded
+/// generator always gives the same palette.
+pub fn random_palette_seeded<R: Rng + ?Sized>(rng: &mut R) -> Palette {
     let mut palette = PALETTES[rng.gen_range(0..PALETTES.len())];
-    palette.colors.shuffle(&mut rng);
+    palette.colors.shuffle(rng);
     palette
 }
 
 const fn hex_to_color(c: usize) -> image::Rgb<u8> {
     let r = (c >> 16) & 0xff;
     let g = (c >> 8) & 0xff;
-    let b = (c >> 0) & 0xff;
+    let b = c & 0xff;
     image::Rgb::<u8>([r as u8, g as u8, b as u8])
 }
 
@@ -93,3 +222,60 @@ const PALETTES: [Palette; 8] = [
         ],
     },
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lerp() {
+        let start = Palette {
+            colors: [Rgb([0, 100, 200]); 5],
+        };
+        let mut end = start;
+        end.colors[2] = Rgb([200, 0, 100]);
+        assert_eq!(start.lerp(&end, 0.0).colors, start.colors);
+        assert_eq!(start.lerp(&end, 1.0).colors, end.colors);
+        let mid = start.lerp(&end, 0.5);
+        assert_eq!(mid.colors[2], Rgb([100, 50, 150]));
+        assert_eq!(mid.colors[0], start.colors[0]);
+        assert_eq!(start.lerp(&end, 2.0).colors, end.colors);
+    }
+
+    #[test]
+    fn test_random_palette_seeded() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let palette = |seed| random_palette_seeded(&mut StdRng::seed_from_u64(seed)).colors;
+        assert_eq!(palette(7), palette(7));
+        assert!((0..10).any(|seed| palette(seed) != palette(7)));
+    }
+
+    #[test]
+    fn test_swatch() {
+        let palette = PALETTES[0];
+        assert_eq!(palette.colors().len(), 5);
+
+        let img = palette.swatch(10);
+        assert_eq!(img.dimensions(), (50, 10));
+        for (i, color) in palette.colors().iter().enumerate() {
+            assert!((0..10).all(|y| img.get_pixel(10 * i as u32 + 5, y) == color));
+        }
+
+        // Labels are drawn in a contrasting color.
+        let img = palette.swatch(54);
+        let block = |x: u32| (0..54).flat_map(move |y| (x..x + 54).map(move |x| (x, y)));
+        for (i, color) in palette.colors().iter().enumerate() {
+            assert!(block(54 * i as u32).any(|(x, y)| img.get_pixel(x, y) != color));
+        }
+    }
+
+    #[test]
+    fn test_gradient() {
+        // The gradient runs from black through every color.
+        let palette = PALETTES[0];
+        assert_eq!(palette.gradient(0.0), Rgb([0, 0, 0]));
+        assert_eq!(palette.gradient(0.2), palette.colors[0]);
+        assert_eq!(palette.gradient(0.6), palette.colors[2]);
+        assert_eq!(palette.gradient(1.0), palette.colors[4]);
+    }
+}
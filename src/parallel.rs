@@ -0,0 +1,122 @@
+//! Data parallelism. With the native feature this is rayon. Without it, e.g. in the browser where
+//! there are no threads, the same methods run sequentially on the calling thread, so the
+//! simulation code reads the same either way.
+
+#[cfg(feature = "native")]
+pub use rayon::prelude;
+
+#[cfg(not(feature = "native"))]
+pub use self::sequential as prelude;
+
+#[cfg(not(feature = "native"))]
+pub mod sequential {
+    use std::{
+        iter::{Cloned, Enumerate, Map, Once, Zip},
+        slice::{Chunks, ChunksExact, ChunksExactMut, Iter, IterMut},
+    };
+
+    /// Sequential stand-in for a rayon parallel iterator. It is a plain iterator, with the few
+    /// rayon adaptors whose signatures differ from the standard ones taking precedence.
+    pub struct Seq<I>(I);
+
+    impl<I: Iterator> Iterator for Seq<I> {
+        type Item = I::Item;
+
+        fn next(&mut self) -> Option<I::Item> {
+            self.0.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+    }
+
+    impl<I: Iterator> Seq<I> {
+        pub fn map<B, F: FnMut(I::Item) -> B>(self, f: F) -> Seq<Map<I, F>> {
+            Seq(self.0.map(f))
+        }
+
+        pub fn cloned<'a, T: 'a + Clone>(self) -> Seq<Cloned<I>>
+        where
+            I: Iterator<Item = &'a T>,
+        {
+            Seq(self.0.cloned())
+        }
+
+        pub fn enumerate(self) -> Seq<Enumerate<I>> {
+            Seq(self.0.enumerate())
+        }
+
+        pub fn zip<J: IntoIterator>(self, other: J) -> Seq<Zip<I, J::IntoIter>> {
+            Seq(self.0.zip(other))
+        }
+
+        /// Fold everything into a single partial result, like rayon does within one thread.
+        pub fn fold<T, ID, F>(self, identity: ID, fold_op: F) -> Seq<Once<T>>
+        where
+            ID: Fn() -> T,
+            F: Fn(T, I::Item) -> T,
+        {
+            Seq(std::iter::once(self.0.fold(identity(), fold_op)))
+        }
+
+        pub fn reduce<ID, F>(self, identity: ID, op: F) -> I::Item
+        where
+            ID: Fn() -> I::Item,
+            F: Fn(I::Item, I::Item) -> I::Item,
+        {
+            self.0.fold(identity(), op)
+        }
+
+        pub fn for_each_init<T, INIT, F>(self, init: INIT, mut op: F)
+        where
+            INIT: Fn() -> T,
+            F: FnMut(&mut T, I::Item),
+        {
+            let mut state = init();
+            self.0.for_each(|item| op(&mut state, item));
+        }
+    }
+
+    /// The parallel methods of shared slices used by the crate.
+    pub trait ParallelSlice<T> {
+        fn par_iter(&self) -> Seq<Iter<'_, T>>;
+        fn par_chunks(&self, chunk_size: usize) -> Seq<Chunks<'_, T>>;
+        fn par_chunks_exact(&self, chunk_size: usize) -> Seq<ChunksExact<'_, T>>;
+    }
+
+    impl<T> ParallelSlice<T> for [T] {
+        fn par_iter(&self) -> Seq<Iter<'_, T>> {
+            Seq(self.iter())
+        }
+
+        fn par_chunks(&self, chunk_size: usize) -> Seq<Chunks<'_, T>> {
+            Seq(self.chunks(chunk_size))
+        }
+
+        fn par_chunks_exact(&self, chunk_size: usize) -> Seq<ChunksExact<'_, T>> {
+            Seq(self.chunks_exact(chunk_size))
+        }
+    }
+
+    /// The parallel methods of mutable slices used by the crate.
+    pub trait ParallelSliceMut<T> {
+        fn par_iter_mut(&mut self) -> Seq<IterMut<'_, T>>;
+        fn par_chunks_exact_mut(&mut self, chunk_size: usize) -> Seq<ChunksExactMut<'_, T>>;
+        fn par_sort_by_key<K: Ord, F: Fn(&T) -> K>(&mut self, f: F);
+    }
+
+    impl<T> ParallelSliceMut<T> for [T] {
+        fn par_iter_mut(&mut self) -> Seq<IterMut<'_, T>> {
+            Seq(self.iter_mut())
+        }
+
+        fn par_chunks_exact_mut(&mut self, chunk_size: usize) -> Seq<ChunksExactMut<'_, T>> {
+            Seq(self.chunks_exact_mut(chunk_size))
+        }
+
+        fn par_sort_by_key<K: Ord, F: Fn(&T) -> K>(&mut self, f: F) {
+            self.sort_by_key(f);
+        }
+    }
+}
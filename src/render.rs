@@ -0,0 +1,300 @@
+use crate::error::Error;
+
+use image::{imageops, Rgb, RgbImage};
+
+use std::f32::consts::TAU;
+
+/// Quantile of the grid values that sets the white point of a rendered grid, before scaling.
+pub const WHITE_POINT_QUANTILE: f32 = 0.999;
+
+/// Multiplier applied to the quantile to get the white point, leaving some headroom for the
+/// brightest trails.
+pub const WHITE_POINT_SCALE: f32 = 1.5;
+
+/// Display gamma used to tonemap the normalized grid values.
+pub const GAMMA: f32 = 2.2;
+
+/// Curve applied to the normalized grid values before gamma correction.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum IntensityCurve {
+    #[default]
+    Linear,
+    /// ln(1 + t / scale) / ln(1 + 1 / scale) of the normalized value t, which lifts values that
+    /// are orders of magnitude below the white point, such as faint exploratory filaments. The
+    /// scale is relative to the white point, and smaller scales lift faint values more.
+    Log(f32),
+}
+
+impl IntensityCurve {
+    /// Map a normalized value in [0, 1] to [0, 1].
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            IntensityCurve::Linear => t,
+            IntensityCurve::Log(scale) => {
+                let scale = scale.max(f32::EPSILON);
+                (t / scale).ln_1p() / scale.recip().ln_1p()
+            }
+        }
+    }
+}
+
+/// How the grid values of a population are turned into intensities once normalized by the white
+/// point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TonemapConfig {
+    pub gamma: f32,
+    /// Multiplier of the normalized values before gamma correction, so that values above 1 / gain
+    /// of the white point saturate.
+    pub gain: f32,
+    pub curve: IntensityCurve,
+}
+
+impl Default for TonemapConfig {
+    fn default() -> Self {
+        TonemapConfig {
+            gamma: GAMMA,
+            gain: 1.0,
+            curve: IntensityCurve::Linear,
+        }
+    }
+}
+
+impl TonemapConfig {
+    /// Normalize a grid value by the white point, scale it by the gain, then apply the curve and
+    /// gamma correction. The result is in [0, 1]. A grid whose white point is zero or not finite,
+    /// e.g. one that is empty or has blown up, renders black, and so do NaN values.
+    pub fn intensity(&self, value: f32, white_point: f32) -> f32 {
+        let t = value / white_point * self.gain;
+        if !white_point.is_finite() || white_point <= 0.0 || t.is_nan() {
+            return 0.0;
+        }
+        self.curve.apply(t.clamp(0.0, 1.0)).powf(1.0 / self.gamma)
+    }
+}
+
+/// Normalize a grid value by the white point and apply the default gamma correction, see
+/// TonemapConfig::intensity.
+pub fn intensity(value: f32, white_point: f32) -> f32 {
+    TonemapConfig::default().intensity(value, white_point)
+}
+
+/// Color of the given hue, saturation and value. The hue is an angle in radians, with red at 0,
+/// green at 2π/3 and blue at 4π/3, and the other two components are in [0, 1].
+pub fn hsv(hue: f32, saturation: f32, value: f32) -> Rgb<u8> {
+    let h = (hue / TAU).rem_euclid(1.0) * 6.0;
+    let (s, v) = (saturation.clamp(0.0, 1.0), value.clamp(0.0, 1.0));
+    let component = |n: f32| {
+        let k = (n + h) % 6.0;
+        let c = v - v * s * (k.min(4.0 - k)).clamp(0.0, 1.0);
+        (c * 255.0).round() as u8
+    };
+    Rgb([component(5.0), component(3.0), component(1.0)])
+}
+
+/// Memory layout of a single pixel in a raw frame buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb8,
+    Rgba8,
+    Bgra8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+        }
+    }
+
+    /// Write an opaque RGB color into a pixel slice of this format.
+    pub(crate) fn write(&self, pixel: &mut [u8], [r, g, b]: [u8; 3]) {
+        match self {
+            PixelFormat::Rgb8 => pixel.copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgba8 => pixel.copy_from_slice(&[r, g, b, 255]),
+            PixelFormat::Bgra8 => pixel.copy_from_slice(&[b, g, r, 255]),
+        }
+    }
+}
+
+/// How the pixels of overlaid images are combined, channel by channel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Sum of the channels, clamped to white.
+    #[default]
+    Add,
+    /// One minus the product of the inverted channels, which brightens like Add but never
+    /// clips.
+    Screen,
+    /// Brightest of the channels.
+    Max,
+}
+
+impl BlendMode {
+    fn blend(self, a: u8, b: u8) -> u8 {
+        match self {
+            BlendMode::Add => a.saturating_add(b),
+            BlendMode::Screen => {
+                let inverse = (255 - a as u16) * (255 - b as u16);
+                255 - ((inverse + 127) / 255) as u8
+            }
+            BlendMode::Max => a.max(b),
+        }
+    }
+}
+
+/// Overlay frames of the same size, e.g. renders of independent simulations with different
+/// palettes, combining them in order with the blend mode.
+pub fn composite(frames: &[RgbImage], mode: BlendMode) -> Result<RgbImage, Error> {
+    let (first, rest) = frames.split_first().ok_or(Error::ZeroCount("frames"))?;
+    let mut result = first.clone();
+    for frame in rest {
+        if frame.dimensions() != result.dimensions() {
+            return Err(Error::ImageSizeMismatch {
+                expected: result.dimensions(),
+                actual: frame.dimensions(),
+            });
+        }
+        for (to, from) in result.iter_mut().zip(frame.iter()) {
+            *to = mode.blend(*to, *from);
+        }
+    }
+    Ok(result)
+}
+
+/// Tile frames into a contact sheet with the given number of columns, filling rows from left to
+/// right. Every cell is as large as the largest frame, frames are placed at its top left corner and
+/// the remaining space is black.
+pub fn montage(frames: &[RgbImage], cols: usize) -> RgbImage {
+    let cols = cols.max(1);
+    let rows = frames.len().div_ceil(cols);
+    let cell_width = frames.iter().map(|frame| frame.width()).max().unwrap_or(0);
+    let cell_height = frames.iter().map(|frame| frame.height()).max().unwrap_or(0);
+
+    let mut sheet = RgbImage::new(cell_width * cols as u32, cell_height * rows as u32);
+    for (i, frame) in frames.iter().enumerate() {
+        let (col, row) = ((i % cols) as u32, (i / cols) as u32);
+        imageops::replace(&mut sheet, frame, col * cell_width, row * cell_height);
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intensity() {
+        assert_eq!(intensity(0.0, 2.0), 0.0);
+        assert_eq!(intensity(2.0, 2.0), 1.0);
+        assert_eq!(intensity(5.0, 2.0), 1.0);
+        assert_eq!(intensity(-1.0, 2.0), 0.0);
+        assert!((intensity(1.0, 2.0) - 0.5_f32.powf(1.0 / GAMMA)).abs() < 1e-6);
+
+        // Degenerate inputs give black instead of NaN.
+        assert_eq!(intensity(0.0, 0.0), 0.0);
+        assert_eq!(intensity(1.0, 0.0), 0.0);
+        assert_eq!(intensity(f32::NAN, 1.0), 0.0);
+        assert_eq!(intensity(1.0, f32::NAN), 0.0);
+        assert_eq!(intensity(1.0, f32::INFINITY), 0.0);
+        assert_eq!(intensity(f32::INFINITY, 1.0), 1.0);
+
+        let config = TonemapConfig {
+            gamma: 1.0,
+            gain: 4.0,
+            curve: IntensityCurve::Linear,
+        };
+        assert_eq!(config.intensity(1.0, 8.0), 0.5);
+        assert_eq!(config.intensity(3.0, 8.0), 1.0);
+        assert_eq!(config.intensity(1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_log_curve() {
+        let linear = TonemapConfig::default();
+        let log = TonemapConfig {
+            curve: IntensityCurve::Log(0.01),
+            ..linear
+        };
+        assert_eq!(log.intensity(0.0, 1.0), 0.0);
+        assert!((log.intensity(1.0, 1.0) - 1.0).abs() < 1e-6);
+        assert_eq!(log.intensity(2.0, 1.0), linear.intensity(2.0, 1.0));
+        let degenerate = TonemapConfig {
+            curve: IntensityCurve::Log(0.0),
+            ..linear
+        };
+        assert!(degenerate.intensity(0.5, 1.0).is_finite());
+
+        // A trail decaying exponentially over five orders of magnitude, rendered to 8 bits: the log
+        // curve brightens the faint values and keeps more of them distinguishable.
+        let ramp: Vec<f32> = (0..256).map(|x| (-(x as f32) / 22.0).exp()).collect();
+        let levels = |config: &TonemapConfig| {
+            let mut levels: Vec<u8> = ramp
+                .iter()
+                .map(|&value| (255.0 * config.intensity(value, 1.0)) as u8)
+                .filter(|&level| level > 0)
+                .collect();
+            levels.dedup();
+            levels.len()
+        };
+        assert!(levels(&log) > levels(&linear));
+        assert!(log.intensity(1e-3, 1.0) > 2.0 * linear.intensity(1e-3, 1.0));
+        // Intensities stay monotonic.
+        let mapped: Vec<f32> = ramp
+            .iter()
+            .map(|&value| log.intensity(value, 1.0))
+            .collect();
+        assert!(mapped.windows(2).all(|pair| pair[0] >= pair[1]));
+    }
+
+    #[test]
+    fn test_hsv() {
+        assert_eq!(hsv(0.0, 1.0, 1.0), Rgb([255, 0, 0]));
+        assert_eq!(hsv(TAU / 3.0, 1.0, 1.0), Rgb([0, 255, 0]));
+        assert_eq!(hsv(-TAU / 3.0, 1.0, 1.0), Rgb([0, 0, 255]));
+        assert_eq!(hsv(TAU / 6.0, 1.0, 1.0), Rgb([255, 255, 0]));
+        assert_eq!(hsv(1.0, 0.0, 0.5), Rgb([128, 128, 128]));
+        assert_eq!(hsv(2.0, 1.0, 0.0), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_montage() {
+        let frames: Vec<_> = (0..3)
+            .map(|i| RgbImage::from_pixel(4, 2, Rgb([50 * (i + 1), 0, 0])))
+            .collect();
+        let sheet = montage(&frames, 2);
+        assert_eq!(sheet.dimensions(), (8, 4));
+        assert_eq!(sheet.get_pixel(0, 0), &Rgb([50, 0, 0]));
+        assert_eq!(sheet.get_pixel(5, 1), &Rgb([100, 0, 0]));
+        assert_eq!(sheet.get_pixel(3, 3), &Rgb([150, 0, 0]));
+        assert_eq!(sheet.get_pixel(7, 3), &Rgb([0, 0, 0]));
+
+        assert_eq!(montage(&[], 3).dimensions(), (0, 0));
+    }
+
+    #[test]
+    fn test_composite() {
+        let half_red = RgbImage::from_pixel(4, 2, Rgb([128, 0, 0]));
+        let blue = RgbImage::from_pixel(4, 2, Rgb([0, 0, 200]));
+        let frames = [half_red.clone(), half_red.clone(), blue];
+        let added = composite(&frames, BlendMode::Add).unwrap();
+        assert!(added.pixels().all(|pixel| pixel.0 == [255, 0, 200]));
+        let screened = composite(&frames[..2], BlendMode::Screen).unwrap();
+        assert!(screened.pixels().all(|pixel| pixel.0 == [192, 0, 0]));
+        let maxed = composite(&frames, BlendMode::Max).unwrap();
+        assert!(maxed.pixels().all(|pixel| pixel.0 == [128, 0, 200]));
+        assert_eq!(composite(&frames[..1], BlendMode::Add).unwrap(), half_red);
+
+        assert_eq!(
+            composite(&[half_red, RgbImage::new(2, 4)], BlendMode::Add),
+            Err(Error::ImageSizeMismatch {
+                expected: (4, 2),
+                actual: (2, 4)
+            })
+        );
+        assert_eq!(
+            composite(&[], BlendMode::Max),
+            Err(Error::ZeroCount("frames"))
+        );
+    }
+}
@@ -0,0 +1,123 @@
+/// Uniform bucketing of points on a periodic domain, for counting the points near a position
+/// without scanning all of them.
+#[derive(Debug, Clone)]
+pub struct SpatialHash {
+    width: f32,
+    height: f32,
+    cols: usize,
+    rows: usize,
+    // Actual cell dimensions, adjusted so that the cells tile the domain exactly.
+    cell_width: f32,
+    cell_height: f32,
+    // Points of cell k are points[cell_start[k]..cell_start[k + 1]].
+    cell_start: Vec<usize>,
+    points: Vec<(f32, f32)>,
+}
+
+impl SpatialHash {
+    /// Bucket points of a width x height periodic domain into square cells of approximately the
+    /// given size.
+    pub fn new(points: &[(f32, f32)], width: usize, height: usize, cell_size: f32) -> Self {
+        let cols = ((width as f32 / cell_size).ceil() as usize).max(1);
+        let rows = ((height as f32 / cell_size).ceil() as usize).max(1);
+        let mut hash = SpatialHash {
+            width: width as f32,
+            height: height as f32,
+            cols,
+            rows,
+            cell_width: width as f32 / cols as f32,
+            cell_height: height as f32 / rows as f32,
+            cell_start: vec![0; cols * rows + 1],
+            points: vec![(0.0, 0.0); points.len()],
+        };
+
+        // Counting sort of the points by cell.
+        let cells: Vec<_> = points.iter().map(|&(x, y)| hash.cell(x, y)).collect();
+        for &cell in &cells {
+            hash.cell_start[cell + 1] += 1;
+        }
+        for k in 0..cols * rows {
+            hash.cell_start[k + 1] += hash.cell_start[k];
+        }
+        let mut next = hash.cell_start.clone();
+        for (&point, &cell) in points.iter().zip(&cells) {
+            hash.points[next[cell]] = point;
+            next[cell] += 1;
+        }
+        hash
+    }
+
+    fn cell(&self, x: f32, y: f32) -> usize {
+        let i = ((x / self.cell_width) as usize).min(self.cols - 1);
+        let j = ((y / self.cell_height) as usize).min(self.rows - 1);
+        j * self.cols + i
+    }
+
+    /// Number of points within a radius of a position, measuring distances across the periodic
+    /// boundaries.
+    pub fn count_within(&self, x: f32, y: f32, radius: f32) -> usize {
+        let span = |center: f32, size: f32, n: usize| {
+            let lo = ((center - radius) / size).floor() as i64;
+            let hi = ((center + radius) / size).floor() as i64;
+            if hi - lo + 1 >= n as i64 {
+                (0, n as i64 - 1)
+            } else {
+                (lo, hi)
+            }
+        };
+        let (i0, i1) = span(x, self.cell_width, self.cols);
+        let (j0, j1) = span(y, self.cell_height, self.rows);
+
+        let mut count = 0;
+        for j in j0..=j1 {
+            let j = j.rem_euclid(self.rows as i64) as usize;
+            for i in i0..=i1 {
+                let i = i.rem_euclid(self.cols as i64) as usize;
+                let k = j * self.cols + i;
+                count += self.points[self.cell_start[k]..self.cell_start[k + 1]]
+                    .iter()
+                    .filter(|&&(px, py)| {
+                        let dx = (px - x).abs() % self.width;
+                        let dy = (py - y).abs() % self.height;
+                        let dx = dx.min(self.width - dx);
+                        let dy = dy.min(self.height - dy);
+                        dx * dx + dy * dy <= radius * radius
+                    })
+                    .count();
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_count_within() {
+        let mut rng = rand::thread_rng();
+        let (width, height) = (64, 32);
+        let points: Vec<(f32, f32)> = (0..1000)
+            .map(|_| (rng.gen_range(0.0..64.0), rng.gen_range(0.0..32.0)))
+            .collect();
+
+        for &cell_size in &[1.0, 5.0, 100.0] {
+            let hash = SpatialHash::new(&points, width, height, cell_size);
+            for _ in 0..50 {
+                let (x, y) = (rng.gen_range(0.0..64.0), rng.gen_range(0.0..32.0));
+                let radius = rng.gen_range(0.0..20.0);
+                let expected = points
+                    .iter()
+                    .filter(|&&(px, py)| {
+                        let dx = (px - x).abs().min(64.0 - (px - x).abs());
+                        let dy = (py - y).abs().min(32.0 - (py - y).abs());
+                        dx * dx + dy * dy <= radius * radius
+                    })
+                    .count();
+                assert_eq!(hash.count_within(x, y, radius), expected);
+            }
+        }
+    }
+}
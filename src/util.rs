@@ -1,4 +1,70 @@
+use rand::{rngs::SmallRng, SeedableRng};
+
+use std::f32::consts::TAU;
+
+/// Wrap x into the [0, max] range periodically, no matter how many periods it is away from it.
 #[inline(always)]
 pub fn wrap(x: f32, max: f32) -> f32 {
-    x - max * ((x > max) as i32 as f32 - (x < 0.0_f32) as i32 as f32)
+    x - max * (x / max).floor()
+}
+
+/// Normalize an angle in radians into [0, 2π), however many turns away from it it is. Unlike wrap,
+/// the upper end is excluded even when rounding would land on it, so sin and cos always get an
+/// argument in their accurate range.
+#[inline(always)]
+pub fn wrap_angle(angle: f32) -> f32 {
+    let wrapped = angle.rem_euclid(TAU);
+    if wrapped < TAU {
+        wrapped
+    } else {
+        0.0
+    }
+}
+
+/// Construct a random number generator that is unique to an agent at a given iteration and fully
+/// determined by the model seed, so that agents can draw random numbers in parallel reproducibly.
+#[inline(always)]
+pub fn agent_rng(seed: u64, agent: usize, iteration: usize) -> SmallRng {
+    let agent = (agent as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let iteration = (iteration as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    SmallRng::seed_from_u64(seed ^ agent ^ iteration.rotate_left(31))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let max = 8.0;
+        assert_eq!(wrap(3.0, max), 3.0);
+        assert_eq!(wrap(0.0, max), 0.0);
+        assert_eq!(wrap(9.0, max), 1.0);
+        assert_eq!(wrap(-1.0, max), 7.0);
+        assert_eq!(wrap(2.5 * max, max), 0.5 * max);
+        assert_eq!(wrap(-1.5 * max, max), 0.5 * max);
+        assert_eq!(wrap(-100.25, max), 3.75);
+        for &x in &[-1e6, -12.3, -1e-3, 7.999, 1e6] {
+            let y = wrap(x, max);
+            assert!((0.0..=max).contains(&y), "wrap({}) = {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_wrap_angle() {
+        assert!((wrap_angle(3.0 * TAU + 0.1) - 0.1).abs() < 1e-5);
+        assert!((wrap_angle(-0.1) - (TAU - 0.1)).abs() < 1e-6);
+        assert!((wrap_angle(-5.0 * TAU + 1.0) - 1.0).abs() < 1e-4);
+        assert_eq!(wrap_angle(0.0), 0.0);
+        assert_eq!(wrap_angle(TAU), 0.0);
+        for &a in &[-1e-9, -1e-30, 1e6, -1e6, TAU - 1e-7] {
+            let wrapped = wrap_angle(a);
+            assert!(
+                (0.0..TAU).contains(&wrapped),
+                "wrap_angle({}) = {}",
+                a,
+                wrapped
+            );
+        }
+    }
 }
@@ -0,0 +1,68 @@
+//! JavaScript bindings for running the simulation in the browser, enabled by the wasm feature.
+//! The browser has no threads, so build for wasm32-unknown-unknown without the native feature,
+//! e.g. `wasm-pack build -- --no-default-features --features wasm`.
+
+use crate::{
+    model::{Model, ModelBuilder},
+    render::PixelFormat,
+};
+
+use wasm_bindgen::prelude::*;
+
+/// A model owned by JavaScript.
+#[wasm_bindgen]
+pub struct Simulation {
+    model: Model,
+    width: usize,
+    height: usize,
+}
+
+/// Construct a model of the given size with the agents split evenly across the populations. The
+/// width and height must be powers of two. The same seed always grows the same pattern.
+#[wasm_bindgen]
+pub fn create_model(
+    width: usize,
+    height: usize,
+    n_particles: usize,
+    n_populations: usize,
+    seed: u32,
+) -> Result<Simulation, JsValue> {
+    let model = ModelBuilder::new()
+        .size(width, height)
+        .particles(n_particles)
+        .populations(n_populations)
+        .seed(seed as u64)
+        .build()
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(Simulation {
+        model,
+        width,
+        height,
+    })
+}
+
+#[wasm_bindgen]
+impl Simulation {
+    /// Perform a single simulation step.
+    pub fn step(&mut self) {
+        self.model.step();
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Render the current frame as row-major RGBA pixels, which can be copied into the ImageData
+    /// of a canvas as is.
+    pub fn render_to_rgba(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.width * self.height * PixelFormat::Rgba8.bytes_per_pixel()];
+        self.model
+            .render_into(&mut buf, PixelFormat::Rgba8)
+            .unwrap();
+        buf
+    }
+}